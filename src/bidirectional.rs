@@ -0,0 +1,550 @@
+use ultraviolet::Vec3;
+
+use crate::{
+    camera::Camera,
+    environment::Environment,
+    material::{Emissive, Material},
+    random::Sampler,
+    ray::Ray,
+    render::{get_sky, tint, Fireflies, T_MAX, T_MIN},
+    spectrum::Wavelengths,
+    tracer::{
+        bvh::Bvh,
+        hittable::{HitRecord, Hittable},
+        lights::Lights,
+    },
+};
+
+/// What a path vertex sits on.
+#[derive(Copy, Clone)]
+enum Kind {
+    /// Point on the lens
+    Camera,
+    /// Point on a light where a light subpath starts
+    Light(Emissive),
+    /// Surface or medium that scatters
+    Scatter,
+}
+
+/// Vertex of a camera or light subpath.
+#[derive(Copy, Clone)]
+struct Vertex<'a> {
+    kind: Kind,
+    point: Vec3,
+    /// Zero inside media, where nothing is foreshortened
+    normal: Vec3,
+    hit: Option<HitRecord<'a>>,
+    /// Ray that arrived at the vertex
+    ray: Ray,
+    /// Throughput from the start of the subpath up to the vertex
+    beta: Vec3,
+    /// Left along a perfectly specular lobe, which no other strategy could have sampled
+    delta: bool,
+    /// Only the hero wavelength reached the vertex
+    dispersed: bool,
+    /// Area densities of sampling the vertex from the subpath it belongs to and from the other end
+    pdf_fwd: f32,
+    pdf_rev: f32,
+}
+
+impl<'a> Vertex<'a> {
+    fn camera(ray: Ray, view_dir: Vec3) -> Vertex<'a> {
+        Vertex {
+            kind: Kind::Camera,
+            point: ray.pos,
+            normal: view_dir,
+            hit: None,
+            ray,
+            beta: Vec3::one(),
+            delta: false,
+            dispersed: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn light(hit: HitRecord<'a>, emitter: Emissive, beta: Vec3, pdf: f32) -> Vertex<'a> {
+        Vertex {
+            kind: Kind::Light(emitter),
+            point: hit.point,
+            normal: hit.normal,
+            hit: Some(hit),
+            ray: Ray::new(hit.point, hit.normal),
+            beta,
+            delta: false,
+            dispersed: false,
+            pdf_fwd: pdf,
+            pdf_rev: 0.0,
+        }
+    }
+
+    #[inline]
+    fn material(&self) -> Option<&'a Material> {
+        self.hit.map(|hit| hit.material)
+    }
+
+    /// Whether the vertex scatters through a lobe that can be joined to another vertex,
+    /// whichever lobe its own subpath went on through.
+    #[inline]
+    fn connectible(&self) -> bool {
+        matches!(self.kind, Kind::Scatter) && self.material().is_some_and(|m| !m.is_delta())
+    }
+
+    /// BSDF times cosine for light leaving towards `point`, RGB.
+    #[inline]
+    fn bsdf(&self, point: Vec3) -> Vec3 {
+        match self.hit {
+            Some(hit) => {
+                hit.material
+                    .eval(self.ray, hit, (point - self.point).normalized())
+                    .0
+            }
+            None => Vec3::zero(),
+        }
+    }
+
+    /// Area density at `next` of this vertex sampling it, having been reached from `prev`.
+    fn pdf(&self, context: &Bidirectional, prev: Option<&Vertex>, next: &Vertex) -> f32 {
+        let dir = (next.point - self.point).normalized();
+        let pdf = match (self.kind, self.hit, prev) {
+            (Kind::Camera, ..) => {
+                context
+                    .camera
+                    .pdf_dir(context.width, context.height, self.point, dir)
+            }
+            (Kind::Light(emitter), ..) => emitter.pdf(self.normal, dir),
+            (Kind::Scatter, Some(hit), Some(prev)) => {
+                let ray = Ray::new(prev.point, self.point - prev.point)
+                    .with_wavelengths(self.ray.wavelengths);
+                hit.material.eval(ray, hit, dir).1
+            }
+            _ => 0.0,
+        };
+        to_area(pdf, self, next)
+    }
+
+    /// Area density at `next` of a light subpath leaving this vertex, when it is on a light.
+    fn pdf_light(&self, next: &Vertex) -> f32 {
+        let dir = (next.point - self.point).normalized();
+        self.material()
+            .and_then(Material::emitter)
+            .map_or(0.0, |emitter| {
+                to_area(emitter.pdf(self.normal, dir), self, next)
+            })
+    }
+
+    /// Area density of a light subpath starting at this vertex, reached from `prev`.
+    fn pdf_light_origin(&self, lights: &Lights, prev: &Vertex) -> f32 {
+        lights.surface_pdf(prev.point, self.point - prev.point, self.point)
+    }
+}
+
+/// Turns a solid angle density at `from` into an area density at `to`.
+#[inline]
+fn to_area(pdf: f32, from: &Vertex, to: &Vertex) -> f32 {
+    let offset = to.point - from.point;
+    let dist_sq = offset.mag_sq();
+    if dist_sq <= 0.0 {
+        return 0.0;
+    }
+    let cosine = if to.normal == Vec3::zero() {
+        1.0
+    } else {
+        to.normal.dot(offset).abs() / dist_sq.sqrt()
+    };
+    pdf * cosine / dist_sq
+}
+
+/// Bidirectional path tracer. Every camera subpath is joined to a light subpath at each
+/// pair of vertices, and the strategies are weighted against each other with the power
+/// heuristic. Light reaching the lens straight from the light subpath lands in other
+/// pixels, so it is handed back as splats. The environment is only found by camera subpaths.
+pub struct Bidirectional<'a> {
+    pub world: &'a Bvh,
+    pub lights: &'a Lights,
+    pub camera: &'a Camera,
+    pub image: &'a Option<Environment>,
+    pub width: usize,
+    pub height: usize,
+    pub max_bounce: u32,
+    pub fireflies: Fireflies,
+}
+
+impl Bidirectional<'_> {
+    /// Radiance along the camera `ray`, as RGB or one value per wavelength. Light traced
+    /// into other pixels is pushed onto `splats` by buffer index, already in RGB.
+    pub fn sample(&self, ray: Ray, sampler: &mut Sampler, splats: &mut Vec<(usize, Vec3)>) -> Vec3 {
+        // Light subpaths reach the lens where it was when the camera ray left it
+        let camera = self.camera.at(ray.time);
+        Bidirectional {
+            camera: &camera,
+            ..*self
+        }
+        .trace(ray, sampler, splats)
+    }
+
+    fn trace(&self, ray: Ray, sampler: &mut Sampler, splats: &mut Vec<(usize, Vec3)>) -> Vec3 {
+        let max_vertices = self.max_bounce as usize + 1;
+        let mut camera_path = Vec::with_capacity(max_vertices);
+        camera_path.push(Vertex::camera(ray, self.camera.view_dir()));
+        let pdf_dir = self
+            .camera
+            .pdf_dir(self.width, self.height, ray.pos, ray.dir);
+        let mut radiance = self.walk(
+            ray,
+            Vec3::one(),
+            pdf_dir,
+            true,
+            max_vertices,
+            sampler,
+            &mut camera_path,
+        );
+
+        let mut light_path = Vec::with_capacity(max_vertices);
+        self.light_subpath(ray, max_vertices - 1, sampler, &mut light_path);
+
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                if s + t < 2 || (s, t) == (1, 1) || s + t > max_vertices {
+                    continue;
+                }
+                radiance += self.connect(&light_path, &camera_path, s, t, sampler, splats);
+            }
+        }
+        radiance
+    }
+
+    /// Starts a light subpath on a light picked by area, and follows it with the wavelengths
+    /// and time of `camera_ray`.
+    fn light_subpath<'a>(
+        &'a self,
+        camera_ray: Ray,
+        max_vertices: usize,
+        sampler: &mut Sampler,
+        path: &mut Vec<Vertex<'a>>,
+    ) {
+        let (r1, r2) = sampler.get_2d();
+        let (r3, r4) = sampler.get_2d();
+        let Some((hit, pdf_pos)) = self.lights.sample_surface(r1, r2) else {
+            return;
+        };
+        let Some(emitter) = hit.material.emitter() else {
+            return;
+        };
+        let (dir, pdf_dir) = emitter.sample(hit.normal, r3, r4);
+        if pdf_dir <= 0.0 || max_vertices == 0 {
+            return;
+        }
+        let radiance = self.emitted(emitter, hit, dir, camera_ray.wavelengths);
+        if radiance == Vec3::zero() {
+            return;
+        }
+        path.push(Vertex::light(hit, emitter, radiance / pdf_pos, pdf_pos));
+        let beta = radiance * hit.normal.dot(dir).abs() / (pdf_pos * pdf_dir);
+        self.walk(
+            Ray::new(hit.point, dir)
+                .with_wavelengths(camera_ray.wavelengths)
+                .with_time(camera_ray.time),
+            beta,
+            pdf_dir,
+            false,
+            max_vertices,
+            sampler,
+            path,
+        );
+    }
+
+    /// Light `emitter` gives off along `dir` from `hit`, tinted.
+    #[inline]
+    fn emitted(
+        &self,
+        emitter: Emissive,
+        hit: HitRecord,
+        dir: Vec3,
+        wavelengths: Option<Wavelengths>,
+    ) -> Vec3 {
+        tint(
+            emitter.emitted(Ray::new(hit.point + dir, -dir), hit),
+            wavelengths,
+        )
+    }
+
+    /// Extends `path` by following `ray` until it escapes, is absorbed or has `max_vertices`
+    /// vertices. Returns the sky seen by a camera subpath that escapes.
+    #[allow(clippy::too_many_arguments)]
+    fn walk<'a>(
+        &'a self,
+        mut ray: Ray,
+        mut beta: Vec3,
+        mut pdf_fwd: f32,
+        from_camera: bool,
+        max_vertices: usize,
+        sampler: &mut Sampler,
+        path: &mut Vec<Vertex<'a>>,
+    ) -> Vec3 {
+        let wavelengths = ray.wavelengths;
+        let start = beta.component_max();
+        let mut dispersed = false;
+        while path.len() < max_vertices {
+            let Some(hit) = self.world.hit(&ray, T_MIN, T_MAX, sampler) else {
+                return if from_camera {
+                    self.fireflies.clamp(
+                        beta * tint(get_sky(ray, self.image), wavelengths),
+                        path.len() - 1,
+                    )
+                } else {
+                    Vec3::zero()
+                };
+            };
+            let prev = path.len() - 1;
+            let mut vertex = Vertex {
+                kind: Kind::Scatter,
+                point: hit.point,
+                normal: if hit.material.is_medium() {
+                    Vec3::zero()
+                } else {
+                    hit.normal
+                },
+                hit: Some(hit),
+                ray,
+                beta,
+                delta: false,
+                dispersed,
+                pdf_fwd: 0.0,
+                pdf_rev: 0.0,
+            };
+            vertex.pdf_fwd = to_area(pdf_fwd, &path[prev], &vertex);
+            path.push(vertex);
+            if !hit.material.scatters() || path.len() == max_vertices {
+                break;
+            }
+
+            let scatter = hit.material.scatter(ray, hit, sampler);
+            if scatter.attenuation == Vec3::zero() {
+                break;
+            }
+            let current = path.len() - 1;
+            path[current].delta = scatter.specular;
+            beta *= tint(scatter.attenuation, wavelengths);
+            if scatter.dispersed && !dispersed {
+                // Only the hero wavelength follows the refracted ray
+                dispersed = true;
+                beta *= Vec3::new(3.0, 0.0, 0.0);
+            }
+            let pdf_rev = if scatter.specular {
+                pdf_fwd = 0.0;
+                0.0
+            } else {
+                pdf_fwd = scatter.pdf;
+                let reverse = Ray::new(hit.point, -scatter.ray.dir).with_wavelengths(wavelengths);
+                hit.material.eval(reverse, hit, -ray.dir).1
+            };
+            path[prev].pdf_rev = to_area(pdf_rev, &path[current], &path[prev]);
+
+            let survival = (beta.component_max() / start).min(1.0);
+            if survival < sampler.get_1d() {
+                break;
+            }
+            beta /= survival;
+            ray = scatter.ray.with_wavelengths(wavelengths);
+        }
+        Vec3::zero()
+    }
+
+    /// Contribution of the path made from the first `s` light and `t` camera vertices,
+    /// weighted against the other ways of sampling it.
+    fn connect(
+        &self,
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        s: usize,
+        t: usize,
+        sampler: &mut Sampler,
+        splats: &mut Vec<(usize, Vec3)>,
+    ) -> Vec3 {
+        let pt = camera_path[t - 1];
+        let wavelengths = pt.ray.wavelengths;
+        let (contribution, sampled) = if s == 0 {
+            // The camera subpath found a light on its own
+            let Some(hit) = pt.hit.filter(|hit| hit.material.is_emissive()) else {
+                return Vec3::zero();
+            };
+            let emitted = hit.material.emitted(pt.ray, hit);
+            (pt.beta * tint(emitted, wavelengths), None)
+        } else if t == 1 {
+            // Straight from the light subpath into the lens
+            let qs = light_path[s - 1];
+            if !qs.connectible() {
+                return Vec3::zero();
+            }
+            let (r1, r2) = sampler.get_2d();
+            let (lens, pdf_lens) = self.camera.sample_lens(r1, r2);
+            let offset = qs.point - lens;
+            let dir = offset.normalized();
+            let Some((x, y)) = self.camera.raster(self.width, self.height, lens, dir) else {
+                return Vec3::zero();
+            };
+            if !self.visible(lens, qs.point, pt.ray.time, sampler) {
+                return Vec3::zero();
+            }
+            let camera = Vertex {
+                pdf_fwd: pdf_lens,
+                ..Vertex::camera(Ray::new(lens, dir), self.camera.view_dir())
+            };
+            let value = qs.beta
+                * tint(qs.bsdf(lens), wavelengths)
+                * (self.camera.importance(self.width, self.height, dir)
+                    * dir.dot(self.camera.view_dir())
+                    / offset.mag_sq());
+            let weight = self.mis_weight(light_path, camera_path, Some(camera), s, t);
+            let splat = self.fireflies.clamp(value * weight, s - 1);
+            let splat = wavelengths.map_or(splat, |w| w.to_rgb(splat));
+            if splat.x.is_finite() && splat.y.is_finite() && splat.z.is_finite() {
+                let row = self.height - 1 - y as usize;
+                splats.push((row * self.width + x as usize, splat));
+            }
+            return Vec3::zero();
+        } else if s == 1 {
+            // Next event estimation, with a fresh point on a light
+            if !pt.connectible() {
+                return Vec3::zero();
+            }
+            let (r1, r2) = sampler.get_2d();
+            let Some((hit, pdf_pos)) = self.lights.sample_surface(r1, r2) else {
+                return Vec3::zero();
+            };
+            let Some(emitter) = hit.material.emitter() else {
+                return Vec3::zero();
+            };
+            let offset = pt.point - hit.point;
+            let dir = offset.normalized();
+            let radiance = self.emitted(emitter, hit, dir, wavelengths);
+            if radiance == Vec3::zero() || !self.visible(pt.point, hit.point, pt.ray.time, sampler)
+            {
+                return Vec3::zero();
+            }
+            let light = Vertex::light(hit, emitter, radiance / pdf_pos, pdf_pos);
+            let value = pt.beta
+                * tint(pt.bsdf(hit.point), wavelengths)
+                * light.beta
+                * (hit.normal.dot(dir).abs() / offset.mag_sq());
+            (value, Some(light))
+        } else {
+            // Join two scattering vertices
+            let qs = light_path[s - 1];
+            if !qs.connectible() || !pt.connectible() {
+                return Vec3::zero();
+            }
+            let value = qs.beta
+                * tint(qs.bsdf(pt.point), wavelengths)
+                * tint(pt.bsdf(qs.point), wavelengths)
+                * pt.beta
+                / (qs.point - pt.point).mag_sq();
+            if value == Vec3::zero() || !self.visible(qs.point, pt.point, pt.ray.time, sampler) {
+                return Vec3::zero();
+            }
+            (self.dispersion(value, &qs, &pt), None)
+        };
+        if contribution == Vec3::zero() {
+            return Vec3::zero();
+        }
+        self.fireflies.clamp(
+            contribution * self.mis_weight(light_path, camera_path, sampled, s, t),
+            s + t - 2,
+        )
+    }
+
+    /// Takes out the second hero wavelength mask when both subpaths were dispersed.
+    #[inline]
+    fn dispersion(&self, value: Vec3, a: &Vertex, b: &Vertex) -> Vec3 {
+        if a.dispersed && b.dispersed {
+            value / 3.0
+        } else {
+            value
+        }
+    }
+
+    /// Whether nothing blocks the segment between `a` and `b`.
+    #[inline]
+    fn visible(&self, a: Vec3, b: Vec3, time: f32, sampler: &mut Sampler) -> bool {
+        let offset = b - a;
+        let distance = offset.mag();
+        self.world
+            .hit(
+                &Ray::new(a, offset).with_time(time),
+                T_MIN,
+                distance * (1.0 - 1e-4) - T_MIN,
+                sampler,
+            )
+            .is_none()
+    }
+
+    /// Power heuristic weight of strategy `(s, t)`, from the ratios of how likely each other
+    /// strategy was to sample the same path. `sampled` replaces the end vertex that was picked
+    /// afresh for the connection.
+    fn mis_weight(
+        &self,
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        sampled: Option<Vertex>,
+        s: usize,
+        t: usize,
+    ) -> f32 {
+        if s + t == 2 {
+            return 1.0;
+        }
+        let mut light = light_path[..s].to_vec();
+        let mut camera = camera_path[..t].to_vec();
+        match sampled {
+            Some(vertex) if s == 1 => light[0] = vertex,
+            Some(vertex) if t == 1 => camera[0] = vertex,
+            _ => (),
+        }
+
+        // Densities at the joined vertices, as if they had been reached from the other side
+        let pt = camera[t - 1];
+        if s == 0 {
+            let pdf = pt.pdf_light_origin(self.lights, &camera[t - 2]);
+            if pdf <= 0.0 {
+                // Lights that can't be sampled are only ever found by camera subpaths
+                return 1.0;
+            }
+            camera[t - 1].pdf_rev = pdf;
+            camera[t - 2].pdf_rev = pt.pdf_light(&camera[t - 2]);
+        } else {
+            let qs = light[s - 1];
+            let qs_prev = s.checked_sub(2).map(|i| light[i]);
+            let pt_prev = t.checked_sub(2).map(|i| camera[i]);
+            camera[t - 1].pdf_rev = qs.pdf(self, qs_prev.as_ref(), &pt);
+            if let Some(pt_prev) = pt_prev {
+                camera[t - 2].pdf_rev = pt.pdf(self, Some(&qs), &pt_prev);
+            }
+            light[s - 1].pdf_rev = pt.pdf(self, pt_prev.as_ref(), &qs);
+            if let Some(qs_prev) = qs_prev {
+                light[s - 2].pdf_rev = qs.pdf(self, Some(&pt), &qs_prev);
+            }
+            light[s - 1].delta = false;
+        }
+        camera[t - 1].delta = false;
+
+        // Deltas can't be evaluated, their densities stand in as one on both sides
+        let remap = |pdf: f32| if pdf != 0.0 { pdf } else { 1.0 };
+        let mut sum = 0.0;
+        let mut ratio = 1.0;
+        for i in (1..t).rev() {
+            ratio *= remap(camera[i].pdf_rev) / remap(camera[i].pdf_fwd);
+            if !camera[i].delta && !camera[i - 1].delta {
+                sum += ratio * ratio;
+            }
+        }
+        ratio = 1.0;
+        for i in (0..s).rev() {
+            ratio *= remap(light[i].pdf_rev) / remap(light[i].pdf_fwd);
+            let delta_before = i > 0 && light[i - 1].delta;
+            if !light[i].delta && !delta_before {
+                sum += ratio * ratio;
+            }
+        }
+        (1.0 + sum).recip()
+    }
+}
@@ -1,9 +1,14 @@
 extern crate fastrand;
 
-use crate::{random, ray::Ray};
+use std::f32::consts::PI;
+
+use crate::{
+    random::{self, Sampler},
+    ray::{Differentials, Ray},
+};
 
 use minifb::Key;
-use ultraviolet::{Rotor3, Vec3};
+use ultraviolet::{Mat3, Rotor3, Slerp, Vec3};
 
 const SENSETIVITY: f32 = 0.0025;
 
@@ -15,6 +20,10 @@ pub struct Camera {
     uvw: [Vec3; 3],
     lens_rd: (f32, f32),
     mouse: Option<(f32, f32)>,
+    /// Opening and closing time of the shutter, `None` when nothing in the scene moves
+    shutter: Option<(f32, f32)>,
+    /// How far the eye travels and how much the view turns by the end of the motion
+    motion: Option<(Vec3, Rotor3)>,
 }
 
 impl Camera {
@@ -46,22 +55,161 @@ impl Camera {
             uvw: [u, v, w],
             lens_rd: (apeture / 2.0, focus_dist),
             mouse: None,
+            shutter: None,
+            motion: None,
+        }
+    }
+
+    /// Opens the shutter between `open` and `close`, as fractions of the motion.
+    pub fn with_shutter(self, (open, close): (f32, f32)) -> Camera {
+        Camera {
+            shutter: Some((open.clamp(0.0, 1.0), close.clamp(0.0, 1.0))),
+            ..self
+        }
+    }
+
+    /// Moves the camera to `eye`, looking at `lookat`, by the end of the motion.
+    pub fn with_motion(self, eye: Vec3, lookat: Vec3) -> Camera {
+        let w = (eye - lookat).normalized();
+        let u = Vec3::unit_y().cross(w).normalized();
+        let start = Mat3::new(self.uvw[0], self.uvw[1], self.uvw[2]);
+        let end = Mat3::new(u, w.cross(u), w);
+        Camera {
+            motion: Some((
+                eye - self.view.0,
+                (end * start.transposed()).into_rotor3().normalized(),
+            )),
+            ..self
         }
     }
 
+    /// Moment within the shutter for one sample. Nothing is drawn from `sampler` when nothing
+    /// moves, so still scenes take the same random numbers as ever.
     #[inline]
-    pub fn gen_ray(&self, width: usize, height: usize, x: f32, y: f32, jx: f32, jy: f32) -> Ray {
-        let rd: Vec3 = self.lens_rd.0 * random::quasirandom_in_cocentric_disk(jx, jy);
-        let offset: Vec3 = rd.x * self.uvw[0] + rd.y * self.uvw[1];
+    pub fn sample_time(&self, sampler: &mut Sampler) -> f32 {
+        self.shutter.map_or(0.0, |(open, close)| {
+            open + (close - open) * sampler.get_1d()
+        })
+    }
+
+    /// The camera where it is at `time`, with the eye moving in a straight line and the view
+    /// turning at a steady rate.
+    pub fn at(&self, time: f32) -> Camera {
+        let Some((translation, rotation)) = self.motion else {
+            return *self;
+        };
+        let turn = Rotor3::identity().slerp(rotation, time).normalized();
+        let eye = self.view.0 + translation * time;
+        let turned = |v: Vec3| v.rotated_by(turn);
+        Camera {
+            view: (eye, eye + turned(self.view.1 - self.view.0), self.view.2),
+            hvc: [
+                turned(self.hvc[0]),
+                turned(self.hvc[1]),
+                eye + turned(self.hvc[2] - self.view.0),
+            ],
+            uvw: self.uvw.map(turned),
+            ..*self
+        }
+    }
+
+    /// Ray through pixel `x`, `y` jittered by `jx`, `jy`. The lens point is drawn from
+    /// `sampler` apart from the jitter, as light tracing assumes, and only with an aperture.
+    #[inline]
+    #[allow(clippy::too_many_arguments)]
+    pub fn gen_ray(
+        &self,
+        width: usize,
+        height: usize,
+        x: f32,
+        y: f32,
+        jx: f32,
+        jy: f32,
+        sampler: &mut Sampler,
+    ) -> Ray {
+        let origin = if self.lens_rd.0 > 0.0 {
+            let (r1, r2) = sampler.get_2d();
+            self.sample_lens(r1, r2).0
+        } else {
+            self.view.0
+        };
+        let offset = origin - self.view.0;
 
         let s = (x + jy) / (width - 1) as f32;
         let t = (y + jx) / (height - 1) as f32;
-        Ray::new(
-            self.view.0 + offset,
-            (self.hvc[2] + (s * self.hvc[0]) + (t * self.hvc[1])) - self.view.0 - offset,
+        let towards = |s: f32, t: f32| {
+            (self.hvc[2] + (s * self.hvc[0]) + (t * self.hvc[1])) - self.view.0 - offset
+        };
+        Ray::new(origin, towards(s, t)).with_differentials(Some(Differentials {
+            x_pos: origin,
+            x_dir: towards(s + 1.0 / (width - 1) as f32, t).normalized(),
+            y_pos: origin,
+            y_dir: towards(s, t + 1.0 / (height - 1) as f32).normalized(),
+        }))
+    }
+
+    /// Distance of `point` from the eye along the view direction.
+    #[inline]
+    pub fn depth(&self, point: Vec3) -> f32 {
+        (self.view.0 - point).dot(self.uvw[2])
+    }
+
+    /// Direction the camera looks in.
+    #[inline]
+    pub fn view_dir(&self) -> Vec3 {
+        -self.uvw[2]
+    }
+
+    /// Point on the lens picked uniformly, with its area density. A pinhole always gives the eye.
+    pub fn sample_lens(&self, r1: f32, r2: f32) -> (Vec3, f32) {
+        let radius = self.lens_rd.0;
+        if radius <= 0.0 {
+            return (self.view.0, 1.0);
+        }
+        let rd = radius * random::quasirandom_in_cocentric_disk(r1, r2);
+        (
+            self.view.0 + rd.x * self.uvw[0] + rd.y * self.uvw[1],
+            (PI * radius * radius).recip(),
         )
     }
 
+    /// Where a ray leaving `lens` along `dir` crosses the film, as the jittered `x` and `y`
+    /// that `gen_ray` takes, or `None` when it misses the frame.
+    pub fn raster(&self, width: usize, height: usize, lens: Vec3, dir: Vec3) -> Option<(f32, f32)> {
+        let cosine = dir.dot(self.view_dir());
+        if cosine <= 0.0 {
+            return None;
+        }
+        let offset = lens + dir * (self.lens_rd.1 / cosine) - self.hvc[2];
+        let x = offset.dot(self.hvc[0]) / self.hvc[0].mag_sq() * (width - 1) as f32;
+        let y = offset.dot(self.hvc[1]) / self.hvc[1].mag_sq() * (height - 1) as f32;
+        ((0.0..width as f32).contains(&x) && (0.0..height as f32).contains(&y)).then_some((x, y))
+    }
+
+    /// Importance over the lens density: what light reaching the lens along `dir` adds to the
+    /// image, spread over the whole film rather than a single pixel.
+    pub fn importance(&self, width: usize, height: usize, dir: Vec3) -> f32 {
+        let cosine = dir.dot(self.view_dir());
+        if cosine <= 0.0 {
+            return 0.0;
+        }
+        // `gen_ray` jitters up to a pixel past the far edges of the frame
+        let area = self.hvc[0].mag()
+            * self.hvc[1].mag()
+            * (width as f32 / (width - 1) as f32)
+            * (height as f32 / (height - 1) as f32);
+        self.lens_rd.1.powi(2) / (area * cosine.powi(4))
+    }
+
+    /// Solid angle density of `gen_ray` choosing `dir` from `lens`.
+    pub fn pdf_dir(&self, width: usize, height: usize, lens: Vec3, dir: Vec3) -> f32 {
+        if self.raster(width, height, lens, dir).is_some() {
+            self.importance(width, height, dir) * dir.dot(self.view_dir())
+        } else {
+            0.0
+        }
+    }
+
     pub fn reset_mouse(&mut self) {
         self.mouse = None;
     }
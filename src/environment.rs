@@ -0,0 +1,156 @@
+use std::f32::consts::PI;
+
+use radiant::Image;
+use ultraviolet::Vec3;
+
+use crate::random::Distribution;
+
+/// Equirectangular HDR map that can be sampled proportionally to its brightness.
+pub struct Environment {
+    image: Image,
+    rows: Distribution,
+    columns: Vec<Distribution>,
+}
+
+impl Environment {
+    pub fn new(image: Image) -> Environment {
+        // Rows near the poles cover less of the sphere, so they are weighted by sin(theta)
+        let columns = (0..image.height)
+            .map(|y| {
+                let sin_theta = (PI * (y as f32 + 0.5) / image.height as f32).sin();
+                Distribution::new(
+                    (0..image.width)
+                        .map(|x| luminance(image.pixel(x, y)) * sin_theta)
+                        .collect(),
+                )
+            })
+            .collect::<Vec<Distribution>>();
+        let rows = Distribution::new(columns.iter().map(|row| row.total()).collect());
+        Environment {
+            image,
+            rows,
+            columns,
+        }
+    }
+
+    #[inline]
+    fn texel(&self, dir: Vec3) -> (usize, usize) {
+        let u = (dir.x.atan2(dir.z) + PI) / (2.0 * PI);
+        let v = (-dir.y).clamp(-1.0, 1.0).acos() / PI;
+        (
+            ((u * self.image.width as f32) as usize).min(self.image.width - 1),
+            (((1.0 - v) * self.image.height as f32) as usize).min(self.image.height - 1),
+        )
+    }
+
+    #[inline]
+    pub fn radiance(&self, dir: Vec3) -> Vec3 {
+        let (x, y) = self.texel(dir);
+        let color = self.image.pixel(x, y);
+        Vec3::new(color.r, color.g, color.b)
+    }
+
+    /// Picks a direction by brightness, returning it with its solid angle density.
+    pub fn sample(&self, r1: f32, r2: f32) -> (Vec3, f32) {
+        let (y, dy) = self.rows.sample(r1);
+        let (x, dx) = self.columns[y].sample(r2);
+        let u = (x as f32 + dx) / self.image.width as f32;
+        let v = 1.0 - (y as f32 + dy) / self.image.height as f32;
+
+        let phi = u * 2.0 * PI - PI;
+        let theta = v * PI;
+        let sin_theta = theta.sin();
+        let dir = Vec3::new(phi.sin() * sin_theta, -theta.cos(), phi.cos() * sin_theta);
+        (dir, self.texel_pdf(x, y, sin_theta))
+    }
+
+    /// Solid angle density of `sample` choosing `dir`.
+    pub fn pdf(&self, dir: Vec3) -> f32 {
+        let (x, y) = self.texel(dir);
+        let sin_theta = (1.0 - dir.y * dir.y).max(0.0).sqrt();
+        self.texel_pdf(x, y, sin_theta)
+    }
+
+    #[inline]
+    fn texel_pdf(&self, x: usize, y: usize, sin_theta: f32) -> f32 {
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        // Each texel spans 1 / (width * height) of the uv square, which maps onto
+        // the sphere with a Jacobian of 2 * PI^2 * sin(theta)
+        let pdf_uv = self.rows.probability(y)
+            * self.columns[y].probability(x)
+            * (self.image.width * self.image.height) as f32;
+        pdf_uv / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+#[inline]
+fn luminance(color: &radiant::Rgb) -> f32 {
+    0.2126 * color.r + 0.7152 * color.g + 0.0722 * color.b
+}
+
+#[cfg(test)]
+mod tests {
+    use radiant::Rgb;
+
+    use crate::random::{quasirandom_on_uniform_sphere, Sampler};
+
+    use super::*;
+
+    /// Dim sky with a bright patch, so sampling is far from uniform.
+    fn environment() -> Environment {
+        let (width, height) = (32, 16);
+        let data = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                let value = if (20..24).contains(&x) && (4..7).contains(&y) {
+                    50.0
+                } else {
+                    0.2 + 0.01 * x as f32
+                };
+                Rgb {
+                    r: value,
+                    g: value * 0.8,
+                    b: value * 0.5,
+                }
+            })
+            .collect();
+        Environment::new(Image {
+            width,
+            height,
+            data,
+        })
+    }
+
+    #[test]
+    fn pdf_matches_sample() {
+        let environment = environment();
+        let mut sampler = Sampler::from_seed(4);
+        for _ in 0..10_000 {
+            let (r1, r2) = sampler.get_2d();
+            let (dir, pdf) = environment.sample(r1, r2);
+            assert!((dir.mag() - 1.0).abs() < 1e-4);
+            let looked_up = environment.pdf(dir);
+            assert!(
+                (looked_up - pdf).abs() <= 1e-3 * pdf,
+                "sampled {pdf}, looked up {looked_up} along {dir:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        let environment = environment();
+        let mut sampler = Sampler::from_seed(5);
+        let count = 400_000;
+        let total: f32 = (0..count)
+            .map(|_| {
+                let (r1, r2) = sampler.get_2d();
+                environment.pdf(quasirandom_on_uniform_sphere(r1, r2))
+            })
+            .sum();
+        let integral = total * 4.0 * PI / count as f32;
+        assert!((integral - 1.0).abs() < 0.03, "integral {integral}");
+    }
+}
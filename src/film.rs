@@ -0,0 +1,287 @@
+use ultraviolet::Vec3;
+
+use crate::{random::Sampler, tracer::bvh::TraversalStats};
+
+// Rec. 709 luminance weights
+const LUMINANCE: Vec3 = Vec3::new(0.2126, 0.7152, 0.0722);
+// Keeps near-black pixels from claiming every sample through their relative error
+const DARK: f32 = 0.01;
+// Fewer samples than this give no usable variance
+const MIN_SAMPLES: u32 = 16;
+// Cap on a single pixel's share of a pass, as a multiple of the sample rate
+const MAX_RATE: u32 = 8;
+
+/// What a camera ray landed on first, for the auxiliary output buffers.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FirstHit {
+    pub albedo: Vec3,
+    pub normal: Vec3,
+    pub depth: f32,
+    pub object_index: u32,
+    pub material_index: u32,
+}
+
+/// Running sums for one pixel, enough to recover its mean and variance,
+/// and the first hits of its camera rays.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Pixel {
+    pub sum: Vec3,
+    pub sum_sq: f32,
+    pub samples: u32,
+    pub first_hits: FirstHit,
+    pub hits: u32,
+}
+
+impl Pixel {
+    #[inline]
+    pub fn add(&mut self, color: Vec3) {
+        let lum = luminance(color);
+        self.sum += color;
+        self.sum_sq += lum * lum;
+        self.samples += 1;
+    }
+
+    /// Sums albedo, normal and depth. Ids can't be averaged, so the first one seen is kept.
+    #[inline]
+    pub fn add_first_hit(&mut self, hit: Option<FirstHit>) {
+        if let Some(hit) = hit {
+            if self.hits == 0 {
+                self.first_hits.object_index = hit.object_index;
+                self.first_hits.material_index = hit.material_index;
+            }
+            self.first_hits.albedo += hit.albedo;
+            self.first_hits.normal += hit.normal;
+            self.first_hits.depth += hit.depth;
+            self.hits += 1;
+        }
+    }
+
+    /// Average of the first hits, with the kept ids. Default when every ray escaped.
+    #[inline]
+    pub fn first_hit(&self) -> FirstHit {
+        if self.hits == 0 {
+            return FirstHit::default();
+        }
+        let n = self.hits as f32;
+        FirstHit {
+            albedo: self.first_hits.albedo / n,
+            normal: self.first_hits.normal.normalized(),
+            depth: self.first_hits.depth / n,
+            ..self.first_hits
+        }
+    }
+
+    #[inline]
+    pub fn mean(&self) -> Vec3 {
+        if self.samples == 0 {
+            Vec3::zero()
+        } else {
+            self.sum / self.samples as f32
+        }
+    }
+
+    /// Standard error of the luminance relative to its mean.
+    #[inline]
+    pub fn relative_error(&self) -> f32 {
+        if self.samples < 2 {
+            return f32::INFINITY;
+        }
+        let n = self.samples as f32;
+        let mean = luminance(self.sum) / n;
+        let variance = ((self.sum_sq / n - mean * mean) * n / (n - 1.0)).max(0.0);
+        (variance / n).sqrt() / (mean.abs() + DARK)
+    }
+
+    #[inline]
+    pub fn converged(&self, threshold: f32) -> bool {
+        self.samples >= MIN_SAMPLES && self.relative_error() < threshold
+    }
+}
+
+/// Accumulated samples of every pixel in the frame.
+#[derive(Clone, Debug)]
+pub struct Film {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Pixel>,
+    pub passes: u32,
+    /// BVH work done during the last pass
+    pub traversal: TraversalStats,
+    /// Light traced into the lens by bidirectional samples, or left by Metropolis chains,
+    /// summed per pixel
+    pub splats: Vec<Vec3>,
+    /// Light subpaths or Metropolis mutations behind `splats`
+    pub light_paths: u64,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize) -> Film {
+        Film {
+            width,
+            height,
+            pixels: vec![Pixel::default(); width * height],
+            passes: 0,
+            traversal: TraversalStats::zero(),
+            splats: vec![Vec3::zero(); width * height],
+            light_paths: 0,
+        }
+    }
+
+    /// Samples each pixel should get in the next pass, on average. Pixels without a reliable
+    /// estimate get `sample_rate`, the rest share the remaining budget by relative error and
+    /// stop once they fall under `threshold`.
+    pub fn sample_shares(&self, sample_rate: u32, threshold: f32) -> Vec<f32> {
+        let errors = self
+            .pixels
+            .iter()
+            .map(|pixel| {
+                if pixel.samples < MIN_SAMPLES || pixel.converged(threshold) {
+                    0.0
+                } else {
+                    pixel.relative_error()
+                }
+            })
+            .collect::<Vec<f32>>();
+        let fresh = self
+            .pixels
+            .iter()
+            .filter(|pixel| pixel.samples < MIN_SAMPLES)
+            .count();
+        let budget = (sample_rate as usize * (self.pixels.len() - fresh)) as f32;
+        let total_error: f32 = errors.iter().sum();
+        self.pixels
+            .iter()
+            .zip(errors)
+            .map(|(pixel, error)| {
+                if pixel.samples < MIN_SAMPLES {
+                    sample_rate as f32
+                } else if total_error > 0.0 {
+                    (budget * error / total_error).min((MAX_RATE * sample_rate) as f32)
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+
+    pub fn converged(&self, threshold: f32) -> bool {
+        self.pixels.iter().all(|pixel| pixel.converged(threshold))
+    }
+
+    /// Relative error averaged over the pixels that have an estimate.
+    pub fn mean_error(&self) -> f32 {
+        let (sum, count) = self
+            .pixels
+            .iter()
+            .map(Pixel::relative_error)
+            .filter(|error| error.is_finite())
+            .fold((0.0, 0), |(sum, count), error| (sum + error, count + 1));
+        if count == 0 {
+            f32::INFINITY
+        } else {
+            sum / count as f32
+        }
+    }
+
+    /// Mean of every pixel, plus its share of the splats. Each light subpath or mutation could
+    /// have landed anywhere on the film, so the splats are scaled by pixels per path.
+    pub fn image(&self) -> Vec<Vec3> {
+        let scale = if self.light_paths > 0 {
+            self.pixels.len() as f32 / self.light_paths as f32
+        } else {
+            0.0
+        };
+        self.pixels
+            .iter()
+            .zip(&self.splats)
+            .map(|(pixel, splat)| pixel.mean() + *splat * scale)
+            .collect()
+    }
+
+    pub fn albedo(&self) -> Vec<Vec3> {
+        self.pixels
+            .iter()
+            .map(|pixel| pixel.first_hit().albedo)
+            .collect()
+    }
+
+    /// Shading normals in world space, zero where nothing was hit.
+    pub fn normals(&self) -> Vec<Vec3> {
+        self.pixels
+            .iter()
+            .map(|pixel| pixel.first_hit().normal)
+            .collect()
+    }
+
+    /// Distance from the camera to the first hit, zero where nothing was hit.
+    pub fn depth(&self) -> Vec<f32> {
+        self.pixels
+            .iter()
+            .map(|pixel| pixel.first_hit().depth)
+            .collect()
+    }
+
+    /// A distinct colour for each object index, black where nothing was hit.
+    pub fn object_ids(&self) -> Vec<Vec3> {
+        self.pixels
+            .iter()
+            .map(|pixel| id_color(pixel.first_hit().object_index))
+            .collect()
+    }
+
+    /// A distinct colour for each material index, black where nothing was hit.
+    pub fn material_ids(&self) -> Vec<Vec3> {
+        self.pixels
+            .iter()
+            .map(|pixel| id_color(pixel.first_hit().material_index))
+            .collect()
+    }
+
+    /// Sample count of the busiest pixel, at least one.
+    pub fn most_samples(&self) -> u32 {
+        self.pixels
+            .iter()
+            .map(|pixel| pixel.samples)
+            .max()
+            .unwrap_or(0)
+            .max(1)
+    }
+
+    /// False colour image of how many samples each pixel took, relative to the busiest pixel.
+    pub fn sample_heatmap(&self) -> Vec<Vec3> {
+        let most = self.most_samples() as f32;
+        self.pixels
+            .iter()
+            .map(|pixel| heatmap(pixel.samples as f32 / most))
+            .collect()
+    }
+}
+
+#[inline]
+pub fn luminance(color: Vec3) -> f32 {
+    color.dot(LUMINANCE)
+}
+
+#[inline]
+fn id_color(index: u32) -> Vec3 {
+    if index == 0 {
+        return Vec3::zero();
+    }
+    let mut sampler = Sampler::from_seed(index as u64);
+    Vec3::new(sampler.get_1d(), sampler.get_1d(), sampler.get_1d()) * 0.8 + Vec3::one() * 0.2
+}
+
+/// Blue through green to red for `value` between zero and one.
+#[inline]
+pub fn heatmap(value: f32) -> Vec3 {
+    let t = value.clamp(0.0, 1.0) * 4.0;
+    Vec3::new(
+        (t - 2.0).clamp(0.0, 1.0),
+        if t < 2.0 {
+            t.min(1.0)
+        } else {
+            (4.0 - t).min(1.0)
+        },
+        (2.0 - t).clamp(0.0, 1.0),
+    )
+}
@@ -1,22 +1,31 @@
 use png::{ColorType::Rgb, Encoder};
 use serde::{self, Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     f32::consts::PI,
     fs::File,
-    io::{BufReader, BufWriter, Read},
+    io::{BufReader, BufWriter, Read, Write},
     path::Path,
     sync::Arc,
 };
-use ultraviolet::Vec3;
+use ultraviolet::{Bivec3, Vec3};
 
+use crate::environment::Environment;
+use crate::film::Film;
 use crate::material::Material;
-use crate::render::Renderer;
+use crate::microfacet::{conductor_names, measured_conductor};
+use crate::render::{self, Renderer};
 use crate::tracer::{
     bvh::Bvh,
-    cube::{ABox, Cube},
+    cube::{ABox, Aabb, Cube},
+    grid::{DensityGrid, VolumeGrid},
     hittable::Hittable,
+    lights::Lights,
+    medium::ConstantMedium,
     mesh::Mesh,
+    moving::Moving,
     sphere::Sphere,
+    tagged::Tagged,
     triangle::Triangle,
 };
 use crate::{camera, Args};
@@ -25,7 +34,7 @@ use crate::{camera, Args};
 struct Scene {
     hdr: Option<String>,
     camera: Camera,
-    materials: std::collections::HashMap<String, Surface>,
+    materials: HashMap<String, Surface>,
     objects: Vec<Object>,
 }
 
@@ -33,7 +42,55 @@ struct Scene {
 struct Object {
     name: Option<String>,
     shape: Shape,
+    #[serde(default)]
     material: String,
+    medium: Option<Medium>,
+    motion: Option<Motion>,
+}
+
+/// How an object moves while the shutter is open, on top of where its shape puts it.
+#[derive(Debug, Deserialize, Serialize)]
+struct Motion {
+    #[serde(default)]
+    start: Transform,
+    end: Transform,
+    /// Line the object turns around, up by default
+    axis: Option<(f32, f32, f32)>,
+    /// Point the object turns about, the middle of its bounds by default
+    pivot: Option<(f32, f32, f32)>,
+}
+
+impl Motion {
+    /// Plane of the turn, oriented so each axis turns the same way as that angle of a box.
+    fn plane(&self) -> Bivec3 {
+        let axis = Vec3::from(self.axis.unwrap_or((0.0, 1.0, 0.0))).normalized();
+        Bivec3::new(axis.z, axis.y, axis.x)
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Transform {
+    #[serde(default)]
+    translation: (f32, f32, f32),
+    /// Angle about the axis in half turns, so 2.0 is a whole turn and more spins further
+    #[serde(default)]
+    rotation: f32,
+}
+
+impl Transform {
+    fn build(&self) -> (Vec3, f32) {
+        (Vec3::from(self.translation), self.rotation * PI)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+enum Medium {
+    Homogeneous(
+        f32,                     // absorption
+        f32,                     // scattering
+        Option<(f32, f32, f32)>, // color
+        Option<f32>,             // anisotropy
+    ),
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -61,6 +118,11 @@ enum Shape {
         Option<(f32, f32, f32)>, // rotation
         bool,                    // cull backface
     ),
+    Grid(
+        String,                  // file path
+        Option<(f32, f32, f32)>, // translation
+        Option<(f32, f32, f32)>, // scale
+    ),
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -77,11 +139,152 @@ enum Surface {
         (f32, f32, f32), // albedo
         Option<f32>,     // roughness
     ),
+    Conductor(
+        ComplexIndex,
+        Option<f32>, // roughness
+    ),
     Dielectric(
-        (f32, f32, f32), // absorption
-        Option<f32>,     // refractive_index
-        Option<f32>,     // roughness
+        (f32, f32, f32),               // absorption
+        Option<f32>,                   // refractive_index
+        Option<f32>,                   // roughness
+        #[serde(default)] Option<f32>, // abbe number, dispersion in spectral mode
+    ),
+    Emissive(
+        (f32, f32, f32), // radiance
+        Option<f32>,     // strength
+        Option<bool>,    // two sided
+    ),
+    Emitting(
+        String,          // base material
+        (f32, f32, f32), // radiance
+        Option<f32>,     // strength
+        Option<bool>,    // two sided
     ),
+    Coated(
+        String,                  // base material
+        Option<f32>,             // refractive index
+        Option<f32>,             // roughness
+        Option<(f32, f32, f32)>, // tint
+        Option<f32>,             // thickness
+    ),
+    // Named fields, since most of them are usually left out
+    Principled {
+        base_color: (f32, f32, f32),
+        #[serde(default)]
+        metallic: Option<f32>,
+        #[serde(default)]
+        roughness: Option<f32>,
+        #[serde(default)]
+        specular: Option<f32>,
+        #[serde(default)]
+        specular_tint: Option<f32>,
+        #[serde(default)]
+        sheen: Option<f32>,
+        #[serde(default)]
+        clearcoat: Option<f32>,
+        #[serde(default)]
+        transmission: Option<f32>,
+        #[serde(default)]
+        ior: Option<f32>,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+enum ComplexIndex {
+    Preset(
+        String, // gold, copper, aluminium, silver or chrome
+    ),
+    Index(
+        (f32, f32, f32), // eta
+        (f32, f32, f32), // k
+    ),
+}
+
+// Deepest chain of materials building on other materials
+const MAX_MATERIAL_DEPTH: u32 = 8;
+
+fn build_material(
+    name: &str,
+    materials: &HashMap<String, Surface>,
+    depth: u32,
+) -> Result<Material, String> {
+    if depth > MAX_MATERIAL_DEPTH {
+        return Err(format!("material \"{name}\" references itself"));
+    }
+    let surface = materials
+        .get(name)
+        .ok_or_else(|| format!("unknown material \"{name}\""))?;
+    Ok(match surface {
+        Surface::Lambertian(albedo) => {
+            if albedo.0.max(albedo.1).max(albedo.2) > 1.0 {
+                println!("warning: \"{name}\" reflects more light than it receives, use Emissive for lights");
+            }
+            Material::lambertian(*albedo)
+        }
+        Surface::Metal(albedo, roughness) => Material::metal(*albedo, roughness.unwrap_or(0.0)),
+        Surface::Conductor(index, roughness) => {
+            let (eta, k) = match index {
+                ComplexIndex::Preset(preset) => measured_conductor(preset)
+                    .map(|(eta, k)| (eta.into(), k.into()))
+                    .ok_or_else(|| {
+                        let names = conductor_names().collect::<Vec<_>>().join(", ");
+                        format!("unknown conductor \"{preset}\" in \"{name}\", try one of {names}")
+                    })?,
+                ComplexIndex::Index(eta, k) => (*eta, *k),
+            };
+            Material::conductor(eta, k, roughness.unwrap_or(0.0))
+        }
+        Surface::Glossy(albedo, reflectance, roughness) => Material::glossy(
+            *albedo,
+            reflectance.unwrap_or(1.0),
+            roughness.unwrap_or(0.0),
+        ),
+        Surface::Dielectric(absorption, refractive_index, roughness, abbe) => Material::dielectric(
+            *absorption,
+            refractive_index.unwrap_or(1.52),
+            roughness.unwrap_or(0.0),
+            abbe.unwrap_or(0.0),
+        ),
+        Surface::Emissive(radiance, strength, two_sided) => Material::emissive(
+            *radiance,
+            strength.unwrap_or(1.0),
+            two_sided.unwrap_or(false),
+        ),
+        Surface::Emitting(base, radiance, strength, two_sided) => Material::emitting(
+            build_material(base, materials, depth + 1)?,
+            *radiance,
+            strength.unwrap_or(1.0),
+            two_sided.unwrap_or(false),
+        ),
+        Surface::Coated(base, refractive_index, roughness, tint, thickness) => Material::coated(
+            build_material(base, materials, depth + 1)?,
+            refractive_index.unwrap_or(1.5),
+            roughness.unwrap_or(0.0),
+            tint.unwrap_or((1.0, 1.0, 1.0)),
+            thickness.unwrap_or(1.0),
+        ),
+        Surface::Principled {
+            base_color,
+            metallic,
+            roughness,
+            specular,
+            specular_tint,
+            sheen,
+            clearcoat,
+            transmission,
+            ior,
+        } => Material::principled(
+            *base_color,
+            metallic.unwrap_or(0.0),
+            roughness.unwrap_or(0.5),
+            specular.unwrap_or(0.5),
+            specular_tint.unwrap_or(0.0),
+            sheen.unwrap_or(0.0),
+            clearcoat.unwrap_or(0.0),
+            transmission.unwrap_or(0.0),
+            ior.unwrap_or(1.45),
+        ),
+    })
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -91,6 +294,11 @@ struct Camera {
     fov: f32,
     focus_dist: f32,
     apeture: f32,
+    /// Where the camera is by the end of the motion, the start keyframe being the above
+    end_position: Option<(f32, f32, f32)>,
+    end_lookat: Option<(f32, f32, f32)>,
+    /// Fractions of the motion the shutter opens and closes at, all of it by default
+    shutter: Option<(f32, f32)>,
 }
 
 pub fn load_scene(scene_file: &Path, args: &Args) -> Result<Renderer, Box<dyn std::error::Error>> {
@@ -105,112 +313,301 @@ pub fn load_scene(scene_file: &Path, args: &Args) -> Result<Renderer, Box<dyn st
     println!("loading hdr");
     let image = if let Ok(f) = File::open(scene.hdr.unwrap_or("".to_string())) {
         let reader = BufReader::new(f);
-        Arc::new(radiant::load(reader).ok())
+        Arc::new(radiant::load(reader).ok().map(Environment::new))
     } else {
         Arc::new(None)
     };
     let mut world: Vec<Arc<dyn Hittable + Send + Sync>> = vec![];
+    let mut lights = Lights::new();
+    // Material indices follow the names alphabetically so they don't change between runs
+    let mut material_names = scene.materials.keys().collect::<Vec<&String>>();
+    material_names.sort();
+    let camera_moves = scene.camera.end_position.is_some() || scene.camera.end_lookat.is_some();
+    let moves = camera_moves || scene.objects.iter().any(|obj| obj.motion.is_some());
     println!("loading objects & materials");
-    scene.objects.into_iter().for_each(|obj| {
-        let material = match *scene.materials.get(&obj.material).unwrap() {
-            Surface::Lambertian(albedo) => Material::lambertian(albedo),
-            Surface::Metal(albedo, roughness) => Material::metal(albedo, roughness.unwrap_or(0.0)),
-            Surface::Glossy(albedo, reflectance, roughness) => {
-                Material::glossy(albedo, reflectance.unwrap_or(1.0), roughness.unwrap_or(0.0))
+    // Built once per name, as coats measure their gain when built
+    let mut built: HashMap<String, Material> = HashMap::new();
+    for (index, obj) in scene.objects.into_iter().enumerate() {
+        let object_index = index as u32 + 1;
+        let material_index = material_names
+            .iter()
+            .position(|name| **name == obj.material)
+            .map_or(0, |position| position as u32 + 1);
+        if obj.material.is_empty() && obj.medium.is_none() {
+            return Err(format!(
+                "object \"{}\" needs a material or a medium",
+                obj.name.unwrap_or_default()
+            )
+            .into());
+        }
+        if let Some(Motion {
+            axis: Some(axis), ..
+        }) = obj.motion
+        {
+            if Vec3::from(axis).mag_sq() == 0.0 {
+                return Err(format!(
+                    "object \"{}\" can't turn around a zero axis",
+                    obj.name.unwrap_or_default()
+                )
+                .into());
             }
-            Surface::Dielectric(absorption, refractive_index, roughness) => Material::dielectric(
+        }
+        if let Shape::Grid(location, translation, scale) = &obj.shape {
+            let Some(Medium::Homogeneous(absorption, scattering, color, anisotropy)) = obj.medium
+            else {
+                return Err(format!("grid \"{location}\" needs a medium").into());
+            };
+            let mut grid = load_grid(Path::new(location))?;
+            let scale = Vec3::from(scale.unwrap_or((1.0, 1.0, 1.0)));
+            let translation = Vec3::from(translation.unwrap_or((0.0, 0.0, 0.0)));
+            let (a, b) = (
+                grid.min * scale + translation,
+                grid.max * scale + translation,
+            );
+            (grid.min, grid.max) = (a.min_by_component(b), a.max_by_component(b));
+            let volume = Arc::new(VolumeGrid::new(
+                grid,
                 absorption,
-                refractive_index.unwrap_or(1.52),
-                roughness.unwrap_or(0.0),
-            ),
-        };
-
-        match obj.shape {
-            Shape::Sphere(position, radius) => {
-                world.push(Arc::new(Sphere::new(
-                    position,
-                    radius.unwrap_or(1.0),
-                    material,
-                )));
-            }
-            Shape::Triangle(vertices) => {
-                let vertices = [
-                    Vec3::from(vertices.0),
-                    Vec3::from(vertices.1),
-                    Vec3::from(vertices.2),
-                ];
-                let normal = (vertices[1] - vertices[0]).cross(vertices[2] - vertices[0]);
-                world.push(Arc::new(Triangle::new(
-                    vertices,
-                    [normal; 3],
-                    true,
-                    material,
-                )));
+                scattering,
+                color.unwrap_or((1.0, 1.0, 1.0)),
+                anisotropy.unwrap_or(0.0),
+            ));
+            for volume in moving(vec![volume], &obj.motion) {
+                world.push(Arc::new(Tagged::new(object_index, 0, volume)));
             }
-            Shape::Box(position, size, rotation) => world.push(Arc::new(Cube::new(
-                position,
-                size.unwrap_or((1.0, 1.0, 1.0)),
-                rotation.unwrap_or((0.0, 0.0, 0.0)),
-                material,
-            ))),
-            Shape::AxisBox(position, size) => {
-                world.push(Arc::new(ABox::new(
-                    position,
-                    size.unwrap_or((1.0, 1.0, 1.0)),
-                    material,
-                )));
+            continue;
+        }
+        if !obj.material.is_empty() {
+            let material = match built.get(&obj.material) {
+                Some(material) => material.clone(),
+                None => {
+                    let material = build_material(&obj.material, &scene.materials, 0)?;
+                    // Coats keep the first regularized version they build, so build it now
+                    if args.regularize > 0.0 {
+                        material.regularized(args.regularize);
+                    }
+                    built.insert(obj.material.clone(), material.clone());
+                    material
+                }
+            };
+            let emissive = material.is_emissive();
+            for object in moving(build_shape(&obj.shape, &material), &obj.motion) {
+                // Meshes and moving shapes can't be sampled directly, so they only light the
+                // scene when hit
+                if emissive && obj.motion.is_none() && !matches!(obj.shape, Shape::Mesh(..)) {
+                    lights.push(object.clone());
+                }
+                world.push(Arc::new(Tagged::new(object_index, material_index, object)));
             }
-            Shape::Mesh(location, translation, scale, rotation, cull_backface) => {
-                let (models, _) = tobj::load_obj(
-                    location,
-                    &tobj::LoadOptions {
-                        single_index: true,
-                        triangulate: true,
-                        ignore_points: true,
-                        ignore_lines: true,
-                    },
-                )
-                .expect("failed to load file");
-                let mut meshes: Vec<Arc<dyn Hittable + Send + Sync>> = Vec::new();
-                models.iter().for_each(|model| {
-                    meshes.push(Arc::new(Mesh::new(
-                        &model.mesh,
-                        Vec3::from(translation.unwrap_or((0.0, 0.0, 0.0))),
-                        Vec3::from(scale.unwrap_or((1.0, 1.0, 1.0))),
-                        Vec3::from(rotation.unwrap_or((0.0, 0.0, 0.0))) * PI,
-                        cull_backface,
-                        material,
-                    )));
-                });
-                world.append(&mut meshes);
+        }
+        if let Some(Medium::Homogeneous(absorption, scattering, color, anisotropy)) = obj.medium {
+            let media = build_shape(&obj.shape, &Material::lambertian((0.0, 0.0, 0.0)))
+                .into_iter()
+                .map(|boundary| {
+                    Arc::new(ConstantMedium::new(
+                        boundary,
+                        absorption,
+                        scattering,
+                        color.unwrap_or((1.0, 1.0, 1.0)),
+                        anisotropy.unwrap_or(0.0),
+                    )) as Arc<dyn Hittable + Send + Sync>
+                })
+                .collect();
+            for medium in moving(media, &obj.motion) {
+                world.push(Arc::new(Tagged::new(object_index, 0, medium)));
             }
         }
-    });
+    }
     println!("building BVH");
     let bvh = Bvh::new(&mut world);
 
+    let position = Vec3::from(scene.camera.position);
+    let lookat = Vec3::from(scene.camera.lookat);
+    let mut camera = camera::Camera::new(
+        position,
+        lookat,
+        Vec3::unit_y(),
+        scene.camera.fov,
+        args.width as f32 / args.height as f32,
+        scene.camera.apeture,
+        scene.camera.focus_dist,
+    );
+    if camera_moves {
+        camera = camera.with_motion(
+            scene.camera.end_position.map_or(position, Vec3::from),
+            scene.camera.end_lookat.map_or(lookat, Vec3::from),
+        );
+    }
+    if moves {
+        camera = camera.with_shutter(scene.camera.shutter.unwrap_or((0.0, 1.0)));
+    }
+
     Ok(Renderer {
         width: args.width,
         height: args.height,
-        camera: camera::Camera::new(
-            Vec3::from(scene.camera.position),
-            Vec3::from(scene.camera.lookat),
-            Vec3::unit_y(),
-            scene.camera.fov,
-            args.width as f32 / args.height as f32,
-            scene.camera.apeture,
-            scene.camera.focus_dist,
-        ),
+        camera,
         world: Arc::new(bvh),
+        lights: Arc::new(lights),
         sample_rate: args.samples,
         max_bounce: args.bounces,
         hdr: image,
-        light_clamp: args.light_clamp,
+        fireflies: args.fireflies(),
+        spectral: args.spectral,
+        noise_threshold: args.noise_threshold,
+        seed: args.seed.unwrap_or_default(),
+        aovs: args.aovs,
+        ao_distance: args.ao_distance,
+        integrator: args.integrator,
+        photons: args.photons,
+        photon_radius: args.photon_radius,
+    })
+}
+
+/// Sets the parts of one object moving together, all turning about the same pivot.
+fn moving(
+    parts: Vec<Arc<dyn Hittable + Send + Sync>>,
+    motion: &Option<Motion>,
+) -> Vec<Arc<dyn Hittable + Send + Sync>> {
+    let Some(motion) = motion else {
+        return parts;
+    };
+    let pivot = motion.pivot.map_or_else(
+        || {
+            parts
+                .iter()
+                .map(|part| part.bounding_box())
+                .reduce(Aabb::surrounding_box)
+                .map_or(Vec3::zero(), |bounds| bounds.center())
+        },
+        Vec3::from,
+    );
+    parts
+        .into_iter()
+        .map(|part| {
+            Arc::new(Moving::new(
+                part,
+                pivot,
+                motion.plane(),
+                motion.start.build(),
+                motion.end.build(),
+            )) as Arc<dyn Hittable + Send + Sync>
+        })
+        .collect()
+}
+
+fn build_shape(shape: &Shape, material: &Material) -> Vec<Arc<dyn Hittable + Send + Sync>> {
+    match shape {
+        Shape::Sphere(position, radius) => vec![Arc::new(Sphere::new(
+            *position,
+            radius.unwrap_or(1.0),
+            material.clone(),
+        ))],
+        Shape::Triangle(vertices) => {
+            let vertices = [
+                Vec3::from(vertices.0),
+                Vec3::from(vertices.1),
+                Vec3::from(vertices.2),
+            ];
+            let normal = (vertices[1] - vertices[0]).cross(vertices[2] - vertices[0]);
+            vec![Arc::new(Triangle::new(
+                vertices,
+                [normal; 3],
+                true,
+                material.clone(),
+            ))]
+        }
+        Shape::Box(position, size, rotation) => vec![Arc::new(Cube::new(
+            *position,
+            size.unwrap_or((1.0, 1.0, 1.0)),
+            rotation.unwrap_or((0.0, 0.0, 0.0)),
+            material.clone(),
+        ))],
+        Shape::AxisBox(position, size) => vec![Arc::new(ABox::new(
+            *position,
+            size.unwrap_or((1.0, 1.0, 1.0)),
+            material.clone(),
+        ))],
+        Shape::Grid(..) => vec![],
+        Shape::Mesh(location, translation, scale, rotation, cull_backface) => {
+            let (models, _) = tobj::load_obj(
+                location,
+                &tobj::LoadOptions {
+                    single_index: true,
+                    triangulate: true,
+                    ignore_points: true,
+                    ignore_lines: true,
+                },
+            )
+            .expect("failed to load file");
+            models
+                .iter()
+                .map(|model| {
+                    Arc::new(Mesh::new(
+                        &model.mesh,
+                        Vec3::from(translation.unwrap_or((0.0, 0.0, 0.0))),
+                        Vec3::from(scale.unwrap_or((1.0, 1.0, 1.0))),
+                        Vec3::from(rotation.unwrap_or((0.0, 0.0, 0.0))) * PI,
+                        *cull_backface,
+                        material.clone(),
+                    )) as Arc<dyn Hittable + Send + Sync>
+                })
+                .collect()
+        }
+    }
+}
+
+/// Reads a density grid: the magic bytes `RVOL`, the resolution as three little-endian `u32`,
+/// the bounds as six `f32` (min then max corner), then one `f32` per voxel with x varying fastest.
+pub fn load_grid(grid_file: &Path) -> Result<DensityGrid, Box<dyn std::error::Error>> {
+    let mut bytes = Vec::new();
+    File::open(grid_file)?.read_to_end(&mut bytes)?;
+    if bytes.len() < 40 || &bytes[0..4] != b"RVOL" {
+        return Err(format!("{} is not a density grid", grid_file.display()).into());
+    }
+    let word = |i: usize| -> [u8; 4] { bytes[4 + 4 * i..8 + 4 * i].try_into().unwrap() };
+    let resolution = [0, 1, 2].map(|i| u32::from_le_bytes(word(i)) as usize);
+    let bound = |i: usize| {
+        Vec3::new(
+            f32::from_le_bytes(word(i)),
+            f32::from_le_bytes(word(i + 1)),
+            f32::from_le_bytes(word(i + 2)),
+        )
+    };
+    let (min, max) = (bound(3), bound(6));
+    // Also turns away NaN corners
+    if !(min.x < max.x && min.y < max.y && min.z < max.z) {
+        return Err(format!("{} has its bounds the wrong way round", grid_file.display()).into());
+    }
+
+    // A corrupt header can claim more voxels than a usize counts
+    let size = resolution
+        .iter()
+        .try_fold(4, |size: usize, &n| size.checked_mul(n))
+        .and_then(|size| size.checked_add(40));
+    if resolution.contains(&0) || size != Some(bytes.len()) {
+        return Err(format!("{} has the wrong number of voxels", grid_file.display()).into());
+    }
+    let density = bytes[40..]
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes(b.try_into().unwrap()).max(0.0))
+        .collect();
+    Ok(DensityGrid {
+        resolution,
+        min,
+        max,
+        density,
     })
 }
 
-pub fn random_scene(lights: bool, diffuse: bool, glossy: bool, metal: bool, glass: bool) -> Bvh {
+pub fn random_scene(
+    lights: bool,
+    diffuse: bool,
+    glossy: bool,
+    metal: bool,
+    glass: bool,
+) -> (Bvh, Lights) {
     let mut world: Vec<Arc<dyn Hittable + Send + Sync>> = vec![];
+    let mut emitters = Lights::new();
     let ground: Material = Material::glossy((0.55, 0.53, 0.56), 0.1, 0.7);
     world.push(Arc::new(ABox::new(
         (-2.0, -0.5, -2.0),
@@ -258,15 +655,17 @@ pub fn random_scene(lights: bool, diffuse: bool, glossy: bool, metal: bool, glas
                         )));
                     } else if lights && choose_mat < 0.9 {
                         // lights
-                        world.push(Arc::new(Sphere::new(
+                        let light: Arc<dyn Hittable + Send + Sync> = Arc::new(Sphere::new(
                             center,
                             0.2,
-                            Material::lambertian((
-                                fastrand::f32() * 6.0,
-                                fastrand::f32() * 6.0,
-                                fastrand::f32() * 6.0,
-                            )),
-                        )));
+                            Material::emissive(
+                                (fastrand::f32(), fastrand::f32(), fastrand::f32()),
+                                6.0,
+                                false,
+                            ),
+                        ));
+                        emitters.push(light.clone());
+                        world.push(light);
                     } else if glass {
                         // glass
                         world.push(Arc::new(Sphere::new(
@@ -276,6 +675,7 @@ pub fn random_scene(lights: bool, diffuse: bool, glossy: bool, metal: bool, glas
                                 (fastrand::f32(), fastrand::f32(), fastrand::f32()),
                                 1.52,
                                 fastrand::f32() * 0.5,
+                                64.0,
                             ),
                         )));
                     }
@@ -284,7 +684,7 @@ pub fn random_scene(lights: bool, diffuse: bool, glossy: bool, metal: bool, glas
         }
     }
 
-    let glass = Material::dielectric((0.1, 0.1, 0.1), 1.52, 0.025);
+    let glass = Material::dielectric((0.1, 0.1, 0.1), 1.52, 0.025, 64.0);
     let gloss = Material::glossy((0.3, 0.2, 0.15), 0.6, 0.3);
     let steel = Material::metal((0.7, 0.5, 0.3), 0.025);
     // let diffuse = Material::lambertian((0.4, 0.2, 0.1));
@@ -294,7 +694,16 @@ pub fn random_scene(lights: bool, diffuse: bool, glossy: bool, metal: bool, glas
     world.push(Arc::new(Sphere::new((-4.0, 1.0, 0.0), 1.0, gloss)));
     // world.push(Arc::new(Sphere::new((-4.5, 1.0, 0.0), 1.0, diffuse)));
 
-    Bvh::new(&mut world)
+    // Every sphere has its own material, so both indices follow the object
+    let mut world = world
+        .into_iter()
+        .enumerate()
+        .map(|(index, object)| {
+            let index = index as u32 + 1;
+            Arc::new(Tagged::new(index, index, object)) as Arc<dyn Hittable + Send + Sync>
+        })
+        .collect::<Vec<_>>();
+    (Bvh::new(&mut world), emitters)
 }
 
 // Define a helper function to convert a 32-bit int color to an RGB triplet
@@ -306,6 +715,69 @@ fn int_to_rgb(color: u32) -> [u8; 3] {
     ]
 }
 
+/// Saves each auxiliary buffer of `film` next to the beauty image. Albedo, normal and depth are
+/// written linear and unclamped as `<stem>_<name>.pfm` for compositing and denoising, the ids
+/// and sample counts as `<stem>_<name>.png`.
+pub fn save_aovs(film: &Film, stem: &str, gamma: f32) -> std::io::Result<()> {
+    let (width, height) = (film.width as u32, film.height as u32);
+    let rgb = |colors: Vec<Vec3>| colors.iter().flat_map(|c| [c.x, c.y, c.z]).collect();
+    let floats: [(&str, Vec<f32>, usize); 3] = [
+        ("albedo", rgb(film.albedo()), 3),
+        ("normal", rgb(film.normals()), 3),
+        ("depth", film.depth(), 1),
+    ];
+    for (name, values, channels) in floats {
+        save_floats_as_image(
+            &values,
+            channels,
+            width,
+            height,
+            &format!("{stem}_{name}.pfm"),
+        )?;
+    }
+
+    let display = |colors: Vec<Vec3>| {
+        colors
+            .iter()
+            .map(|color| render::pack(&color.map(|c| c.clamp(0.0, 1.0).powf(gamma))))
+            .collect::<Vec<u32>>()
+    };
+    let buffers = [
+        ("object", display(film.object_ids())),
+        ("material", display(film.material_ids())),
+        (
+            "samples",
+            film.sample_heatmap().iter().map(render::pack).collect(),
+        ),
+    ];
+    for (name, colors) in buffers {
+        save_colors_as_image(&colors, width, height, &format!("{stem}_{name}.png"))?;
+    }
+    Ok(())
+}
+
+/// Writes `values`, `channels` floats per pixel from the top row down, as a portable float
+/// map: grey for one channel and RGB for three.
+pub fn save_floats_as_image(
+    values: &[f32],
+    channels: usize,
+    width: u32,
+    height: u32,
+    filename: &str,
+) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(filename)?);
+    let kind = if channels == 1 { "Pf" } else { "PF" };
+    // A negative scale marks the floats as little endian
+    write!(writer, "{kind}\n{width} {height}\n-1.0\n")?;
+    // Rows are stored from the bottom up
+    for row in values.chunks(width as usize * channels).rev() {
+        for value in row {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+    }
+    writer.flush()
+}
+
 // Define the function to save the colors as an image
 pub fn save_colors_as_image(
     colors: &[u32],
@@ -334,3 +806,80 @@ pub fn save_colors_as_image(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// Writes a grid file into the temp directory, under a name unique to the test.
+    fn grid_file(name: &str, resolution: [u32; 3], bounds: [f32; 6], voxels: usize) -> PathBuf {
+        let mut bytes = b"RVOL".to_vec();
+        resolution
+            .iter()
+            .for_each(|n| bytes.extend_from_slice(&n.to_le_bytes()));
+        bounds
+            .iter()
+            .for_each(|b| bytes.extend_from_slice(&b.to_le_bytes()));
+        (0..voxels).for_each(|i| bytes.extend_from_slice(&(i as f32).to_le_bytes()));
+        let path = std::env::temp_dir().join(format!("rust_raytracer_{name}.rvol"));
+        File::create(&path).unwrap().write_all(&bytes).unwrap();
+        path
+    }
+
+    const BOUNDS: [f32; 6] = [-1.0, 0.0, -1.0, 1.0, 2.0, 1.0];
+
+    #[test]
+    fn loads_a_grid() {
+        let path = grid_file("loads", [2, 3, 4], BOUNDS, 24);
+        let grid = load_grid(&path).unwrap();
+        assert_eq!(grid.resolution, [2, 3, 4]);
+        assert_eq!(grid.min, Vec3::new(-1.0, 0.0, -1.0));
+        assert_eq!(grid.max, Vec3::new(1.0, 2.0, 1.0));
+        assert_eq!(grid.density.len(), 24);
+    }
+
+    #[test]
+    fn rejects_the_wrong_number_of_voxels() {
+        for (name, resolution, voxels) in [
+            ("short", [2, 3, 4], 23),
+            ("long", [2, 3, 4], 25),
+            ("empty", [0, 3, 4], 0),
+            // Sizes that overflow unless the count is checked
+            ("overflow", [u32::MAX, u32::MAX, 0x4000_0001], 4),
+        ] {
+            let error = load_grid(&grid_file(name, resolution, BOUNDS, voxels)).unwrap_err();
+            assert!(
+                error.to_string().contains("wrong number of voxels"),
+                "{name}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_inverted_bounds() {
+        for (name, bounds) in [
+            ("inverted", [1.0, 0.0, -1.0, -1.0, 2.0, 1.0]),
+            ("flat", [-1.0, 2.0, -1.0, 1.0, 2.0, 1.0]),
+            ("nan", [-1.0, f32::NAN, -1.0, 1.0, 2.0, 1.0]),
+        ] {
+            let error = load_grid(&grid_file(name, [1, 1, 1], bounds, 1)).unwrap_err();
+            assert!(
+                error.to_string().contains("wrong way round"),
+                "{name}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path = std::env::temp_dir().join("rust_raytracer_other.rvol");
+        File::create(&path)
+            .unwrap()
+            .write_all(b"not a grid at all, just some text")
+            .unwrap();
+        let error = load_grid(&path).unwrap_err();
+        assert!(error.to_string().contains("not a density grid"), "{error}");
+    }
+}
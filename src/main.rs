@@ -1,15 +1,15 @@
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 use camera::Camera;
-use clap::Parser;
+use clap::{error::ErrorKind, CommandFactory, Parser};
 use minifb::{Key, Window, WindowOptions};
 
 use std::{
-    f32::INFINITY,
     fs::File,
     io::BufReader,
     path::Path,
-    sync::Arc,
+    sync::{mpsc, Arc},
+    thread,
     time::{self, Duration},
 };
 use ultraviolet::Vec3;
@@ -17,17 +17,28 @@ use ultraviolet::Vec3;
 use rayon::prelude::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
 
 use crate::{
+    environment::Environment,
+    film::{heatmap, Film},
     filter::bilateral_filter,
-    render::{Mode, Renderer},
+    render::{Fireflies, Integrator, Mode, Renderer},
+    tile::TileOrder,
 };
 
+pub mod bidirectional;
 pub mod camera;
+pub mod environment;
+pub mod film;
 pub mod filter;
 pub mod io;
 pub mod material;
+pub mod metropolis;
+pub mod microfacet;
+pub mod photon;
 pub mod random;
 pub mod ray;
 pub mod render;
+pub mod spectrum;
+pub mod tile;
 pub mod tracer;
 
 extern crate clap;
@@ -45,9 +56,17 @@ pub struct Args {
     #[arg(short, long, default_value_t = 128)]
     samples: u32,
 
-    /// Number of frames to cumulate
-    #[arg(short, long, default_value_t = 64)]
-    passes: u32,
+    /// Number of frames to cumulate [default: 64 without a time limit or error target]
+    #[arg(short, long)]
+    passes: Option<u32>,
+
+    /// Stop rendering before the next pass would run past this many seconds
+    #[arg(long, value_parser = seconds)]
+    time_limit: Option<Duration>,
+
+    /// Stop rendering once the mean relative error drops below this, not with metropolis
+    #[arg(long)]
+    target_error: Option<f32>,
 
     /// Max number of times a ray can bounce
     #[arg(short, long, default_value_t = 8)]
@@ -65,17 +84,106 @@ pub struct Args {
     #[arg(short, long, default_value_t = 2.2)]
     gamma: f32,
 
-    /// Max light brightness
-    #[arg(short, long, default_value_t = INFINITY)]
-    light_clamp: f32,
+    /// Max brightness of light that scattered once on its way to the camera
+    #[arg(short = 'l', long, alias = "light-clamp", default_value_t = f32::INFINITY)]
+    direct_clamp: f32,
+
+    /// Max brightness of light that scattered more than once on its way to the camera
+    #[arg(long, default_value_t = f32::INFINITY)]
+    indirect_clamp: f32,
+
+    /// Smallest metal and glossy roughness after a rough bounce, 0 keeps every lobe as it is
+    #[arg(long, default_value_t = 0.0)]
+    regularize: f32,
+
+    /// Trace hero wavelengths instead of RGB, for dispersion in glass
+    #[arg(long, default_value_t = false)]
+    spectral: bool,
+
+    /// Light transport algorithm for the final image
+    #[arg(long, value_enum, default_value_t = Integrator::Path)]
+    integrator: Integrator,
+
+    /// Caustic photons shot from the lights each pass, 0 leaves caustics to the path tracer
+    #[arg(long, default_value_t = 0)]
+    photons: usize,
+
+    /// Gather radius of caustic photons in the first pass, shrunk a little every pass after
+    #[arg(long, default_value_t = 0.1)]
+    photon_radius: f32,
+
+    /// Stop sampling a pixel once its relative error drops below this, 0 never stops
+    #[arg(long, default_value_t = 0.0)]
+    noise_threshold: f32,
+
+    /// Show how many samples each pixel took instead of the image while rendering
+    #[arg(long, default_value_t = false)]
+    sample_heatmap: bool,
+
+    /// Pixel size of the square tiles rendered in parallel
+    #[arg(long, default_value_t = 32)]
+    tile_size: usize,
+
+    /// Order tiles are rendered in
+    #[arg(long, value_enum, default_value_t = TileOrder::Spiral)]
+    tile_order: TileOrder,
+
+    /// Start each pass with the tile under the mouse cursor
+    #[arg(long, default_value_t = false)]
+    mouse_first: bool,
+
+    /// Mode the preview starts in, keys 1 to 5 switch between them
+    #[arg(long, value_enum, default_value_t = Mode::Image)]
+    mode: Mode,
+
+    /// Farthest an occluder can be in ambient occlusion mode
+    #[arg(long, default_value_t = 1.0)]
+    ao_distance: f32,
+
+    /// Also save linear albedo, normal and depth as .pfm, and object, material and sample images
+    #[arg(long, default_value_t = false)]
+    aovs: bool,
+
+    /// Seed for every random choice, the same seed renders the same image [default: random]
+    #[arg(long)]
+    seed: Option<u64>,
 
     /// apply bilateral filter after render to reduce noise
     #[arg(short, long, default_value_t = false)]
     filter: bool,
 }
 
+impl Args {
+    fn fireflies(&self) -> Fireflies {
+        Fireflies {
+            direct_clamp: self.direct_clamp,
+            indirect_clamp: self.indirect_clamp,
+            regularize: self.regularize,
+        }
+    }
+}
+
+/// Parses a length of time in seconds, turning away negative, infinite and NaN values.
+fn seconds(value: &str) -> Result<Duration, String> {
+    let seconds: f32 = value.parse().map_err(|err| format!("{err}"))?;
+    Duration::try_from_secs_f32(seconds).map_err(|_| format!("{value} isn't a length of time"))
+}
+
 fn main() {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    // Metropolis only splats, so it has no per-pixel error to reach a target with
+    if args.integrator == Integrator::Metropolis && args.target_error.is_some() {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--target-error needs an integrator that estimates its error, not metropolis",
+            )
+            .exit();
+    }
+    let seed = *args.seed.get_or_insert_with(|| fastrand::u64(..));
+    println!("seed {seed}");
+    // Scene generation and BVH construction draw from the global generator
+    fastrand::seed(seed);
 
     let mut window = Window::new(
         "Rust Pathtracer",
@@ -109,7 +217,8 @@ fn main() {
     println!("press Enter to start render");
     let mode = preview_render(&mut window, &mut renderer, &args);
 
-    let mut buffer = render_image(&mut window, &mut renderer, &args, mode);
+    let film = render_image(&mut window, &mut renderer, &args, mode);
+    let mut buffer = film.image();
 
     if args.filter {
         (1..4).for_each(|i| {
@@ -143,19 +252,23 @@ fn main() {
             .update_with_buffer(final_img.as_slice(), renderer.width, renderer.height)
             .unwrap();
         if window.is_key_down(Key::Enter) {
+            let stem = format!(
+                "output/{}",
+                time::SystemTime::now()
+                    .duration_since(time::SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis()
+            );
             io::save_colors_as_image(
                 final_img.as_slice(),
                 renderer.width as u32,
                 renderer.height as u32,
-                &format!(
-                    "output/{}.png",
-                    time::SystemTime::now()
-                        .duration_since(time::SystemTime::UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis()
-                ),
+                &format!("{stem}.png"),
             )
             .ok();
+            if args.aovs {
+                io::save_aovs(&film, &stem, gamma).ok();
+            }
             break;
         }
     }
@@ -165,7 +278,7 @@ fn make_default_setup(args: &Args) -> Renderer {
     // Load HDR
     let image = if let Ok(f) = File::open(r".\scene\HDR\lythwood_room.hdr") {
         let reader = BufReader::new(f);
-        Arc::new(radiant::load(reader).ok())
+        Arc::new(radiant::load(reader).ok().map(Environment::new))
     } else {
         Arc::new(None)
     };
@@ -182,23 +295,32 @@ fn make_default_setup(args: &Args) -> Renderer {
     );
 
     // World setup
-    let world = Arc::new(io::random_scene(true, true, true, true, true));
+    let (world, lights) = io::random_scene(true, true, true, true, true);
     Renderer {
         width: args.width,
         height: args.height,
         camera: *camera,
-        world,
+        world: Arc::new(world),
+        lights: Arc::new(lights),
         sample_rate: args.samples,
         max_bounce: args.bounces,
         hdr: image,
-        light_clamp: INFINITY,
+        fireflies: args.fireflies(),
+        spectral: args.spectral,
+        noise_threshold: args.noise_threshold,
+        seed: args.seed.unwrap_or_default(),
+        aovs: args.aovs,
+        ao_distance: args.ao_distance,
+        integrator: args.integrator,
+        photons: args.photons,
+        photon_radius: args.photon_radius,
     }
 }
 
 fn preview_render(window: &mut Window, renderer: &mut Renderer, args: &Args) -> Mode {
     let mut buffer: Vec<Vec3>;
     let gamma = args.gamma.recip();
-    let mut mode = Mode::Image;
+    let mut mode = args.mode;
     while window.is_open() && !window.is_key_down(Key::Escape) {
         if window.is_key_down(Key::Key1) {
             mode = Mode::Image;
@@ -206,6 +328,10 @@ fn preview_render(window: &mut Window, renderer: &mut Renderer, args: &Args) ->
             mode = Mode::Colors;
         } else if window.is_key_down(Key::Key3) {
             mode = Mode::Normals;
+        } else if window.is_key_down(Key::Key4) {
+            mode = Mode::AmbientOcclusion;
+        } else if window.is_key_down(Key::Key5) {
+            mode = Mode::TraversalCost;
         }
         if window.get_mouse_down(minifb::MouseButton::Left) {
             window.set_cursor_style(minifb::CursorStyle::ResizeAll);
@@ -221,8 +347,9 @@ fn preview_render(window: &mut Window, renderer: &mut Renderer, args: &Args) ->
         }
         if window.is_key_pressed(Key::Enter, minifb::KeyRepeat::Yes) {
             println!(
-                "Rendering with {0} passes with {1} rays per pixel",
-                args.passes, renderer.sample_rate
+                "Rendering with {0} with {1} rays per pixel",
+                Budget::new(args),
+                renderer.sample_rate
             );
             break;
         }
@@ -242,40 +369,161 @@ fn preview_render(window: &mut Window, renderer: &mut Renderer, args: &Args) ->
     mode
 }
 
-fn render_image(
-    window: &mut Window,
-    renderer: &mut Renderer,
-    args: &Args,
-    mode: Mode,
-) -> Vec<Vec3> {
-    let mut buffer: Vec<Vec3> = vec![Vec3::zero(); args.width * args.height];
+/// When a render should stop: after a number of passes, a time limit or an error target,
+/// whichever comes first.
+struct Budget {
+    passes: u32,
+    time_limit: Option<Duration>,
+    target_error: Option<f32>,
+}
+
+impl Budget {
+    fn new(args: &Args) -> Budget {
+        let open_ended = args.time_limit.is_some() || args.target_error.is_some();
+        Budget {
+            passes: args
+                .passes
+                .unwrap_or(if open_ended { u32::MAX } else { 64 }),
+            time_limit: args.time_limit,
+            target_error: args.target_error,
+        }
+    }
+
+    fn done(&self, pass: u32, elapsed: Duration, error: f32) -> bool {
+        if pass == 0 {
+            return false;
+        }
+        let next_pass_end = elapsed + elapsed / pass;
+        pass >= self.passes
+            || self.time_limit.is_some_and(|limit| next_pass_end > limit)
+            || self.target_error.is_some_and(|target| error < target)
+    }
+
+    /// Time left until the first criterion is met. Error falls with the square root of the
+    /// sample count, so the error target is projected from the passes done so far.
+    fn eta(&self, pass: u32, elapsed: Duration, error: f32) -> Duration {
+        let per_pass = elapsed.as_secs_f32() / pass.max(1) as f32;
+        let mut left = self.passes.saturating_sub(pass) as f32 * per_pass;
+        if let Some(limit) = self.time_limit {
+            left = left.min((limit.as_secs_f32() - elapsed.as_secs_f32()).max(0.0));
+        }
+        if let Some(target) = self.target_error {
+            let passes_needed = pass as f32 * (error / target).powi(2);
+            left = left.min((passes_needed - pass as f32).max(0.0) * per_pass);
+        }
+        Duration::from_secs_f32(left.min(u32::MAX as f32))
+    }
+}
+
+impl std::fmt::Display for Budget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut limits = Vec::new();
+        if self.passes < u32::MAX {
+            limits.push(format!("{} passes", self.passes));
+        }
+        if let Some(limit) = self.time_limit {
+            limits.push(format!("a {} second limit", limit.as_secs_f32()));
+        }
+        if let Some(target) = self.target_error {
+            limits.push(format!("a {}% error target", target * 100.0));
+        }
+        write!(f, "{}", limits.join(" or "))
+    }
+}
+
+fn render_image(window: &mut Window, renderer: &mut Renderer, args: &Args, mode: Mode) -> Film {
+    let mut film = Film::new(args.width, args.height);
     let gamma = args.gamma.recip();
+    let budget = Budget::new(args);
+    let mut display = vec![0; args.width * args.height];
     let mut pass: u32 = 0;
+    let mut error = f32::INFINITY;
     let mut total_times: Duration = Default::default();
     // Render loop
     while window.is_open() && !window.is_key_down(Key::Escape) {
-        if pass < args.passes && window.is_open() {
+        if film.converged(args.noise_threshold) {
+            println!("every pixel converged");
+            break;
+        }
+        if !budget.done(pass, total_times, error) && window.is_open() {
             pass += 1;
+            let mut tiles = tile::tiles(args.width, args.height, args.tile_size, args.tile_order);
+            if args.mouse_first {
+                if let Some((x, y)) = window.get_mouse_pos(minifb::MouseMode::Discard) {
+                    tile::prioritize(&mut tiles, x as usize, y as usize);
+                }
+            }
             total_times += {
                 println!("rendering...");
                 let now = std::time::Instant::now();
-                buffer = renderer.render(buffer.as_slice(), mode);
+                let most = film.most_samples() as f32;
+                let (sender, receiver) = mpsc::channel();
+                film = thread::scope(|scope| {
+                    let (renderer, film, tiles) = (&*renderer, &film, &tiles);
+                    let worker = scope.spawn(move || {
+                        renderer.render(film, mode, tiles, |tile, pixels| {
+                            sender.send((*tile, pixels.to_vec())).ok();
+                        })
+                    });
+                    // Show tiles as they come in, taking whatever finished since the last update
+                    while let Ok(first) = receiver.recv() {
+                        std::iter::once(first).chain(receiver.try_iter()).for_each(
+                            |(tile, pixels)| {
+                                tile.pixels(args.width)
+                                    .zip(pixels)
+                                    .for_each(|(index, pixel)| {
+                                        display[index] = if args.sample_heatmap {
+                                            render::pack(&heatmap(pixel.samples as f32 / most))
+                                        } else {
+                                            render::to_rgb(&pixel.mean(), gamma)
+                                        };
+                                    });
+                            },
+                        );
+                        window
+                            .update_with_buffer(display.as_slice(), args.width, args.height)
+                            .unwrap();
+                    }
+                    worker.join().unwrap()
+                });
                 let elapsed_time = now.elapsed();
                 println!("Frame took {} seconds.", elapsed_time.as_secs_f32());
+                display = if args.sample_heatmap {
+                    film.sample_heatmap()
+                        .par_iter()
+                        .map(render::pack)
+                        .collect::<Vec<u32>>()
+                } else {
+                    film.image()
+                        .par_iter()
+                        .map(|color| render::to_rgb(color, gamma))
+                        .collect::<Vec<u32>>()
+                };
                 window
-                    .update_with_buffer(
-                        buffer
-                            .par_iter()
-                            .map(|color| render::to_rgb(&(*color / pass as f32), gamma))
-                            .collect::<Vec<u32>>()
-                            .as_slice(),
-                        args.width,
-                        args.height,
-                    )
+                    .update_with_buffer(display.as_slice(), args.width, args.height)
                     .unwrap();
-                println!("finished pass {pass}");
                 elapsed_time
             };
+            let traversal = film.traversal;
+            println!(
+                "traced {} rays with {} box and {} primitive tests, {:.1} tests per ray",
+                traversal.rays,
+                traversal.box_tests,
+                traversal.primitive_tests,
+                (traversal.box_tests + traversal.primitive_tests) as f32
+                    / traversal.rays.max(1) as f32
+            );
+            error = film.mean_error();
+            // Metropolis images are all splats, which carry no error estimate
+            let error_note = if error.is_finite() {
+                format!(", error {:.2}%", error * 100.0)
+            } else {
+                String::new()
+            };
+            println!(
+                "finished pass {pass}{error_note}, about {:.0} seconds left",
+                budget.eta(pass, total_times, error).as_secs_f32()
+            );
         } else if window.is_open() {
             break;
         }
@@ -284,6 +532,68 @@ fn render_image(
         "Average frame time {} seconds.",
         total_times.as_secs_f32() / pass as f32
     );
-    // Normalize samples
-    buffer.iter().map(|color| (*color / pass as f32)).collect()
+    film
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(flags: &[&str]) -> Budget {
+        Budget::new(&Args::parse_from(
+            std::iter::once("rust_raytracer").chain(flags.iter().copied()),
+        ))
+    }
+
+    #[test]
+    fn parses_seconds() {
+        assert_eq!(seconds("2.5"), Ok(Duration::from_millis(2500)));
+        assert_eq!(seconds("0"), Ok(Duration::ZERO));
+        for value in ["-1", "NaN", "inf", "ten"] {
+            assert!(seconds(value).is_err(), "{value} was accepted");
+        }
+    }
+
+    #[test]
+    fn passes_default_only_without_other_limits() {
+        assert_eq!(budget(&[]).passes, 64);
+        assert_eq!(budget(&["--time-limit", "10"]).passes, u32::MAX);
+        assert_eq!(budget(&["--target-error", "0.01"]).passes, u32::MAX);
+        assert_eq!(budget(&["--time-limit", "10", "--passes", "3"]).passes, 3);
+    }
+
+    #[test]
+    fn stops_at_the_first_limit_met() {
+        let secs = Duration::from_secs;
+        let passes = budget(&["--passes", "4"]);
+        assert!(!passes.done(0, secs(0), f32::INFINITY));
+        assert!(!passes.done(3, secs(3), f32::INFINITY));
+        assert!(passes.done(4, secs(4), f32::INFINITY));
+
+        // Stops before a pass that would run past the limit, at the average pass time so far
+        let time = budget(&["--time-limit", "10"]);
+        assert!(!time.done(3, secs(6), f32::INFINITY));
+        assert!(time.done(3, secs(9), f32::INFINITY));
+
+        let error = budget(&["--target-error", "0.01"]);
+        assert!(!error.done(0, secs(0), 0.0));
+        assert!(!error.done(2, secs(2), 0.02));
+        assert!(error.done(2, secs(2), 0.005));
+    }
+
+    #[test]
+    fn estimates_the_time_left() {
+        let secs = Duration::from_secs;
+        assert_eq!(budget(&["--passes", "10"]).eta(4, secs(8), 1.0), secs(12));
+        assert_eq!(
+            budget(&["--time-limit", "20"]).eta(4, secs(8), 1.0),
+            secs(12)
+        );
+        // Halving the error takes four times the passes
+        let error = budget(&["--target-error", "0.01"]);
+        assert_eq!(error.eta(4, secs(8), 0.02), secs(24));
+        assert_eq!(error.eta(4, secs(8), 0.005), secs(0));
+        let both = budget(&["--target-error", "0.01", "--time-limit", "20"]);
+        assert_eq!(both.eta(4, secs(8), 0.02), secs(12));
+    }
 }
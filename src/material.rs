@@ -1,25 +1,98 @@
+use std::{
+    borrow::Cow,
+    f32::consts::{FRAC_1_PI, PI},
+    sync::OnceLock,
+};
+
 use crate::{
-    random::{quasirandom_in_unit_sphere, quasirandom_on_hemisphere, random_in_unit_sphere},
-    ray::Ray,
+    film::luminance,
+    microfacet::{
+        conductor_index, fresnel_average, fresnel_conductor, fresnel_dielectric, refract,
+        transmission_half, Ggx, Gtr1,
+    },
+    random::{quasirandom_on_cosine_sphere, quasirandom_on_hemisphere, Sampler},
+    ray::{Differentials, Onb, Ray},
     tracer::hittable::HitRecord,
 };
 
-use ultraviolet::Vec3;
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use ultraviolet::{Lerp, Vec3};
 
 const AIR_INDEX: f32 = 1.00028;
+// Below this roughness a lobe is treated as a perfect mirror
+const SMOOTH: f32 = 0.001;
 
 #[derive(Debug, Copy, Clone)]
 pub struct Scatter {
     pub attenuation: Vec3,
     pub ray: Ray,
+    pub pdf: f32,
+    pub specular: bool,
+    /// Direction depends on the hero wavelength, so the other wavelengths can't follow it.
+    pub dispersed: bool,
+    /// Ratio of the refractive indices on the incoming and outgoing side, for refracted rays.
+    pub eta: f32,
 }
 
 impl Scatter {
-    pub fn new(attenuation: Vec3, ray: Ray) -> Scatter {
-        Scatter { attenuation, ray }
+    pub fn new(attenuation: Vec3, ray: Ray, pdf: f32, specular: bool) -> Scatter {
+        Scatter {
+            attenuation,
+            ray,
+            pdf,
+            specular,
+            dispersed: false,
+            eta: 1.0,
+        }
+    }
+
+    #[inline]
+    fn absorbed(hit: HitRecord) -> Scatter {
+        Scatter::new(Vec3::zero(), Ray::new(hit.point, hit.normal), 0.0, false)
     }
 }
 
+/// Shading frame with the normal flipped towards the incoming ray.
+#[inline]
+fn facing_frame(ray: Ray, hit: HitRecord) -> Onb {
+    if ray.dir.dot(hit.normal) > 0.0 {
+        Onb::from_w(&-hit.normal)
+    } else {
+        Onb::from_w(&hit.normal)
+    }
+}
+
+/// Differentials of a mirrored or refracted ray, as in pbrt. The neighbouring rays leave from
+/// where they crossed the surface, bent by how the normal turns between pixels.
+fn specular_differentials(ray: Ray, hit: HitRecord, scatter: Scatter) -> Option<Differentials> {
+    let differentials = ray.differentials?;
+    let footprint = hit.footprint(&ray)?;
+    let (dndx, dndy) = footprint.normal_steps(hit.uv);
+    let (wo, wi) = (-ray.dir, scatter.ray.dir);
+    let reflected = wo.dot(hit.normal) * wi.dot(hit.normal) > 0.0;
+    // Normal on the side light arrives from
+    let side = if wo.dot(hit.normal) < 0.0 { -1.0 } else { 1.0 };
+    let n = hit.normal * side;
+    let bend = |dir: Vec3, dndx: Vec3| {
+        let (dwo, dndx) = (-dir - wo, dndx * side);
+        let dcos = dwo.dot(n) + wo.dot(dndx);
+        if reflected {
+            wi - dwo + 2.0 * (wo.dot(n) * dndx + dcos * n)
+        } else {
+            let eta = scatter.eta;
+            let mu = eta * wo.dot(n) - wi.dot(n).abs();
+            let dmu = (eta - eta * eta * wo.dot(n) / wi.dot(n).abs()) * dcos;
+            wi - eta * dwo + mu * dndx + dmu * n
+        }
+    };
+    Some(Differentials {
+        x_pos: hit.point + footprint.dpdx,
+        x_dir: bend(differentials.x_dir, dndx),
+        y_pos: hit.point + footprint.dpdy,
+        y_dir: bend(differentials.y_dir, dndy),
+    })
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Lambertian {
     pub albedo: Vec3,
@@ -30,7 +103,16 @@ impl Lambertian {
         let direction = quasirandom_on_hemisphere(hit.normal, r1, r2);
         let attenuation = self.albedo;
         let scattered_ray = Ray::new(hit.point, direction);
-        Scatter::new(attenuation, scattered_ray)
+        let pdf = hit.normal.dot(scattered_ray.dir).max(0.0) * FRAC_1_PI;
+        Scatter::new(attenuation, scattered_ray, pdf, false)
+    }
+
+    pub fn eval(self, _: Ray, hit: HitRecord, dir: Vec3) -> (Vec3, f32) {
+        let cosine = hit.normal.dot(dir);
+        if cosine <= 0.0 {
+            return (Vec3::zero(), 0.0);
+        }
+        (self.albedo * (cosine * FRAC_1_PI), cosine * FRAC_1_PI)
     }
 }
 
@@ -42,40 +124,154 @@ pub struct Glossy {
 }
 
 impl Glossy {
+    /// Share of light taken by the coating, also used as the chance of sampling it.
+    #[inline]
+    fn reflection_prob(self, cosine: f32) -> f32 {
+        schlick(cosine, AIR_INDEX, 1.0 + self.reflectance)
+    }
+
     pub fn scatter(self, ray: Ray, hit: HitRecord, r1: f32, r2: f32) -> Scatter {
-        let jittered_normal =
-            (hit.normal + (random_in_unit_sphere() * self.roughness)).normalized();
-        let cosine = (-ray.dir).dot(jittered_normal);
-        let reflection_prob = schlick(cosine, AIR_INDEX, 1.0 + self.reflectance);
+        let frame = facing_frame(ray, hit);
+        let wo = frame.to_local(-ray.dir);
+        let reflection_prob = self.reflection_prob(wo.z);
 
-        let (color, out_dir) = if fastrand::f32() <= reflection_prob {
-            (Vec3::one() * 0.9, ray.dir.reflected(jittered_normal))
+        let wi = if r1 < reflection_prob {
+            if self.roughness <= SMOOTH {
+                return Scatter::new(
+                    Vec3::one() * 0.9,
+                    Ray::new(hit.point, ray.dir.reflected(frame.w)),
+                    0.0,
+                    true,
+                );
+            }
+            let h = Ggx::new(self.roughness).sample_h(wo, r1 / reflection_prob, r2);
+            (-wo).reflected(h)
         } else {
-            let direction = quasirandom_on_hemisphere(hit.normal, r1, r2);
-            (self.albedo, direction)
+            quasirandom_on_cosine_sphere((r1 - reflection_prob) / (1.0 - reflection_prob), r2)
         };
-        Scatter::new(color, Ray::new(hit.point, out_dir))
+
+        let (value, pdf) = self.eval_local(wo, wi);
+        if pdf <= 0.0 {
+            return Scatter::absorbed(hit);
+        }
+        Scatter::new(
+            value / pdf,
+            Ray::new(hit.point, frame.local(wi)),
+            pdf,
+            false,
+        )
+    }
+
+    pub fn eval(self, ray: Ray, hit: HitRecord, dir: Vec3) -> (Vec3, f32) {
+        let frame = facing_frame(ray, hit);
+        self.eval_local(frame.to_local(-ray.dir), frame.to_local(dir))
+    }
+
+    fn eval_local(self, wo: Vec3, wi: Vec3) -> (Vec3, f32) {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return (Vec3::zero(), 0.0);
+        }
+        let reflection_prob = self.reflection_prob(wo.z);
+        let diffuse_pdf = (1.0 - reflection_prob) * wi.z * FRAC_1_PI;
+        let diffuse = self.albedo * diffuse_pdf;
+        if self.roughness <= SMOOTH {
+            return (diffuse, diffuse_pdf);
+        }
+        let (specular, specular_pdf) = Ggx::new(self.roughness)
+            .eval_reflection(wo, wi, |_| Vec3::one() * (0.9 * reflection_prob));
+        (
+            diffuse + specular,
+            diffuse_pdf + reflection_prob * specular_pdf,
+        )
     }
 }
 
+/// GGX conductor with a complex index of refraction. Light that bounces between facets more
+/// than once is added back as its own lobe, so rough metals keep their brightness.
 #[derive(Debug, Copy, Clone)]
 pub struct Metal {
+    /// Reflectance head on, also the colour given to the albedo buffer.
     pub albedo: Vec3,
     pub roughness: f32,
+    pub eta: Vec3,
+    pub k: Vec3,
+    /// Cosine weighted average of the Fresnel term, for the multiple scattering lobe.
+    pub fresnel_average: Vec3,
 }
 
 impl Metal {
-    pub fn scatter(self, ray: Ray, hit: HitRecord, _: f32, _: f32) -> Scatter {
-        let jittered_normal =
-            (hit.normal + (random_in_unit_sphere() * self.roughness)).normalized();
-        let out_dir = ray.dir.reflected(jittered_normal);
+    pub fn new(eta: Vec3, k: Vec3, roughness: f32) -> Metal {
+        Metal {
+            albedo: fresnel_conductor(1.0, eta, k),
+            roughness,
+            eta,
+            k,
+            fresnel_average: fresnel_average(|cosine| fresnel_conductor(cosine, eta, k)),
+        }
+    }
+
+    #[inline]
+    fn fresnel(self, cosine: f32) -> Vec3 {
+        fresnel_conductor(cosine, self.eta, self.k)
+    }
+
+    /// Chance of sampling the multiple scattering lobe, the share of light single scattering
+    /// misses.
+    #[inline]
+    fn multiple_prob(self, ggx: Ggx, cosine: f32) -> f32 {
+        (1.0 - ggx.albedo(cosine)).clamp(0.0, 1.0)
+    }
+
+    pub fn scatter(self, ray: Ray, hit: HitRecord, r1: f32, r2: f32) -> Scatter {
+        let frame = facing_frame(ray, hit);
+        let wo = frame.to_local(-ray.dir);
+        if self.roughness <= SMOOTH {
+            return Scatter::new(
+                self.fresnel(wo.z),
+                Ray::new(hit.point, ray.dir.reflected(frame.w)),
+                0.0,
+                true,
+            );
+        }
+
+        let ggx = Ggx::new(self.roughness);
+        let multiple_prob = self.multiple_prob(ggx, wo.z);
+        let wi = if r1 < multiple_prob {
+            quasirandom_on_cosine_sphere(r1 / multiple_prob, r2)
+        } else {
+            let r1 = (r1 - multiple_prob) / (1.0 - multiple_prob);
+            (-wo).reflected(ggx.sample_h(wo, r1, r2))
+        };
+        let (value, pdf) = self.eval_local(wo, wi);
+        if pdf <= 0.0 {
+            return Scatter::absorbed(hit);
+        }
         Scatter::new(
-            {
-                let cosine = (-ray.dir).dot(jittered_normal);
-                (self.albedo + (Vec3::one() - self.albedo) * (1.0 - cosine).powi(5))
-                    .clamped(Vec3::zero(), Vec3::one())
-            },
-            Ray::new(hit.point, out_dir),
+            value / pdf,
+            Ray::new(hit.point, frame.local(wi)),
+            pdf,
+            false,
+        )
+    }
+
+    pub fn eval(self, ray: Ray, hit: HitRecord, dir: Vec3) -> (Vec3, f32) {
+        if self.roughness <= SMOOTH {
+            return (Vec3::zero(), 0.0);
+        }
+        let frame = facing_frame(ray, hit);
+        self.eval_local(frame.to_local(-ray.dir), frame.to_local(dir))
+    }
+
+    fn eval_local(self, wo: Vec3, wi: Vec3) -> (Vec3, f32) {
+        let ggx = Ggx::new(self.roughness);
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return (Vec3::zero(), 0.0);
+        }
+        let (single, single_pdf) = ggx.eval_reflection(wo, wi, |cosine| self.fresnel(cosine));
+        let multiple_prob = self.multiple_prob(ggx, wo.z);
+        (
+            single + ggx.eval_multiple(wo, wi, self.fresnel_average),
+            (1.0 - multiple_prob) * single_pdf + multiple_prob * wi.z * FRAC_1_PI,
         )
     }
 }
@@ -85,6 +281,8 @@ pub struct Dielectric {
     pub albedo: Vec3,
     pub refractive_index: f32,
     pub roughness: f32,
+    /// Abbe number of the glass, zero for no dispersion.
+    pub abbe: f32,
 }
 
 fn schlick(cosine: f32, ni: f32, nt: f32) -> f32 {
@@ -93,16 +291,35 @@ fn schlick(cosine: f32, ni: f32, nt: f32) -> f32 {
     (r0 + (1.0 - r0) * (1.0 - cosine).powi(5)).clamp(0.0, 1.0)
 }
 
+fn schlick_color(albedo: Vec3, cosine: f32) -> Vec3 {
+    (albedo + (Vec3::one() - albedo) * (1.0 - cosine).powi(5)).clamped(Vec3::zero(), Vec3::one())
+}
+
 impl Dielectric {
-    pub fn scatter(self, ray: Ray, hit: HitRecord, r1: f32, r2: f32) -> Scatter {
-        let jittered_normal =
-            (hit.normal + (quasirandom_in_unit_sphere(r1, r2) * self.roughness)).normalized();
-        let (outward_normal, (ni, nt), cosine, color) = if ray.dir.dot(hit.normal) > 0.0 {
+    /// Cauchy's equation fitted through `refractive_index` at the sodium D line and the Abbe number.
+    #[inline]
+    fn index_at(self, wavelength: Option<f32>) -> f32 {
+        match wavelength {
+            Some(lambda) if self.abbe > 0.0 => {
+                // Fraunhofer D, F and C lines in micrometres
+                let (d, f, c) = (0.5893_f32, 0.4861_f32, 0.6563_f32);
+                let b = (self.refractive_index - 1.0) / (self.abbe * (f.powi(-2) - c.powi(-2)));
+                let a = self.refractive_index - b * d.powi(-2);
+                a + b * (lambda * 1e-3).powi(-2)
+            }
+            _ => self.refractive_index,
+        }
+    }
+
+    /// Indices on the incoming and outgoing side, and the colour light picks up at the hit:
+    /// Beer-Lambert absorption over the path inside when leaving the glass.
+    #[inline]
+    fn interface(self, ray: Ray, hit: HitRecord) -> ((f32, f32), Vec3) {
+        let refractive_index = self.index_at(ray.wavelengths.map(|w| w.hero()));
+        if ray.dir.dot(hit.normal) > 0.0 {
             let absorbance = self.albedo * -hit.t * 2.0;
             (
-                -jittered_normal,
-                (self.refractive_index, AIR_INDEX),
-                ((ray.dir).dot(jittered_normal)),
+                (refractive_index, AIR_INDEX),
                 Vec3::new(
                     f32::exp(absorbance.x),
                     f32::exp(absorbance.y),
@@ -110,37 +327,725 @@ impl Dielectric {
                 ),
             )
         } else {
-            (
-                jittered_normal,
-                (AIR_INDEX, self.refractive_index),
-                ((-ray.dir).dot(jittered_normal)),
-                Vec3::one() * 0.9,
-            )
+            ((AIR_INDEX, refractive_index), Vec3::one() * 0.9)
+        }
+    }
+
+    pub fn scatter(self, ray: Ray, hit: HitRecord, sampler: &mut Sampler) -> Scatter {
+        let mut scatter = if self.roughness <= SMOOTH {
+            self.scatter_smooth(ray, hit, sampler)
+        } else {
+            self.scatter_rough(ray, hit, sampler)
+        };
+        scatter.dispersed = self.abbe > 0.0 && ray.wavelengths.is_some();
+        scatter
+    }
+
+    fn scatter_smooth(self, ray: Ray, hit: HitRecord, sampler: &mut Sampler) -> Scatter {
+        let ((ni, nt), color) = self.interface(ray, hit);
+        let (outward_normal, cosine) = if ray.dir.dot(hit.normal) > 0.0 {
+            (-hit.normal, ray.dir.dot(hit.normal))
+        } else {
+            (hit.normal, (-ray.dir).dot(hit.normal))
         };
         if (ni / nt) * (1.0 - (cosine * cosine)).sqrt() <= 1.0 {
             let reflection_prob = schlick(cosine, ni, nt);
 
-            let out_dir = if fastrand::f32() <= reflection_prob {
-                ray.dir.reflected(outward_normal)
+            if sampler.get_1d() <= reflection_prob {
+                let out_dir = ray.dir.reflected(outward_normal);
+                Scatter::new(color, Ray::new(hit.point, out_dir), 0.0, true)
             } else {
-                ray.dir.refracted(outward_normal, ni / nt)
-            };
-            Scatter::new(color, Ray::new(hit.point, out_dir))
+                let out_dir = ray.dir.refracted(outward_normal, ni / nt);
+                Scatter {
+                    eta: ni / nt,
+                    ..Scatter::new(color, Ray::new(hit.point, out_dir), 0.0, true)
+                }
+            }
         } else {
             Scatter::new(
                 color,
                 Ray::new(hit.point, ray.dir.reflected(outward_normal)),
+                0.0,
+                true,
             )
         }
     }
+
+    /// Walter et al.'s GGX glass. A visible facet is sampled, then reflected off or refracted
+    /// through by its Fresnel term, so total internal reflection comes out on its own.
+    fn scatter_rough(self, ray: Ray, hit: HitRecord, sampler: &mut Sampler) -> Scatter {
+        let ((ni, nt), color) = self.interface(ray, hit);
+        let eta = nt / ni;
+        let frame = facing_frame(ray, hit);
+        let wo = frame.to_local(-ray.dir);
+        let (r1, r2) = sampler.get_2d();
+        let h = Ggx::new(self.roughness).sample_h(wo, r1, r2);
+        let refracted = match refract(wo, h, eta) {
+            Some(wi) if sampler.get_1d() >= fresnel_dielectric(wo.dot(h), eta) => Some(wi),
+            _ => None,
+        };
+        let wi = refracted.unwrap_or_else(|| (-wo).reflected(h));
+
+        let (value, pdf) = self.eval_local(wo, wi, eta);
+        if pdf <= 0.0 {
+            return Scatter::absorbed(hit);
+        }
+        let scatter = Scatter::new(
+            color * (value / pdf),
+            Ray::new(hit.point, frame.local(wi)),
+            pdf,
+            false,
+        );
+        if refracted.is_some() {
+            Scatter {
+                eta: ni / nt,
+                ..scatter
+            }
+        } else {
+            scatter
+        }
+    }
+
+    pub fn eval(self, ray: Ray, hit: HitRecord, dir: Vec3) -> (Vec3, f32) {
+        if self.roughness <= SMOOTH {
+            return (Vec3::zero(), 0.0);
+        }
+        let ((ni, nt), color) = self.interface(ray, hit);
+        let frame = facing_frame(ray, hit);
+        let (value, pdf) = self.eval_local(frame.to_local(-ray.dir), frame.to_local(dir), nt / ni);
+        (color * value, pdf)
+    }
+
+    /// Both lobes with Fresnel included, and the density of sampling `wi` through a visible
+    /// facet and then picking its lobe by Fresnel.
+    fn eval_local(self, wo: Vec3, wi: Vec3, eta: f32) -> (f32, f32) {
+        let ggx = Ggx::new(self.roughness);
+        if wi.z > 0.0 {
+            let h = (wo + wi).normalized();
+            let (value, pdf) = ggx.eval_reflection(wo, wi, |cosine| {
+                Vec3::one() * fresnel_dielectric(cosine, eta)
+            });
+            (value.x, pdf * fresnel_dielectric(wo.dot(h), eta))
+        } else {
+            let h = transmission_half(wo, wi, eta);
+            let (value, pdf) = ggx.eval_transmission(wo, wi, eta);
+            (value, pdf * (1.0 - fresnel_dielectric(wo.dot(h), eta)))
+        }
+    }
+}
+
+// Parts of Disney's model the scene file leaves fixed
+const SHEEN_TINT: f32 = 0.5;
+const CLEARCOAT_ALPHA: f32 = 0.001;
+const CLEARCOAT_F0: f32 = 0.04;
+
+/// Disney's principled BSDF. A Burley diffuse base with sheen sits under a GGX specular layer
+/// that turns into a conductor with `metallic`, and `transmission` swaps the base for rough
+/// glass. A clearcoat lobe goes on top. Each lobe is picked by its expected share of the light
+/// and importance sampled on its own.
+#[derive(Debug, Copy, Clone)]
+pub struct Principled {
+    pub base_color: Vec3,
+    pub metallic: f32,
+    /// Perceptual roughness, squared for the width of the specular lobes.
+    pub roughness: f32,
+    /// Dielectric reflectance at normal incidence, with 0.5 giving 4%.
+    pub specular: f32,
+    pub specular_tint: f32,
+    pub sheen: f32,
+    pub clearcoat: f32,
+    pub transmission: f32,
+    pub ior: f32,
 }
 
+/// Where a principled hit is being shaded from, and the chance of picking each lobe there.
 #[derive(Debug, Copy, Clone)]
+struct Lobes {
+    /// Index across the surface over the index on the side of the ray
+    eta: f32,
+    diffuse: f32,
+    specular: f32,
+    clearcoat: f32,
+    transmission: f32,
+}
+
+impl Principled {
+    #[inline]
+    fn alpha(self) -> f32 {
+        self.roughness * self.roughness
+    }
+
+    #[inline]
+    fn smooth(self) -> bool {
+        self.alpha() <= SMOOTH
+    }
+
+    /// Base colour with its brightness taken out.
+    #[inline]
+    fn tint(self) -> Vec3 {
+        let lum = luminance(self.base_color);
+        if lum > 0.0 {
+            self.base_color / lum
+        } else {
+            Vec3::one()
+        }
+    }
+
+    #[inline]
+    fn diffuse_weight(self) -> f32 {
+        (1.0 - self.metallic) * (1.0 - self.transmission)
+    }
+
+    #[inline]
+    fn transmission_weight(self) -> f32 {
+        (1.0 - self.metallic) * self.transmission
+    }
+
+    /// Reflectance of the specular layer, blending Schlick towards the base colour for metals
+    /// with the exact dielectric Fresnel where the surface transmits.
+    #[inline]
+    fn fresnel(self, cosine: f32, eta: f32) -> Vec3 {
+        let tinted = Vec3::one().lerp(self.tint(), self.specular_tint);
+        let f0 = (0.08 * self.specular * tinted).lerp(self.base_color, self.metallic);
+        let glass = self.transmission_weight();
+        (1.0 - glass) * schlick_color(f0, cosine)
+            + Vec3::one() * (glass * fresnel_dielectric(cosine, eta))
+    }
+
+    fn lobes(self, ray: Ray, hit: HitRecord, wo: Vec3) -> Lobes {
+        let outside = ray.dir.dot(hit.normal) < 0.0;
+        let eta = if outside { self.ior } else { self.ior.recip() };
+        // Inside glass only the interface is left, but opaque surfaces look the same both ways
+        let surface = if outside || self.transmission_weight() <= 0.0 {
+            1.0
+        } else {
+            0.0
+        };
+        let diffuse = surface * self.diffuse_weight() * luminance(self.base_color).max(0.0);
+        let specular = luminance(self.fresnel(wo.z, eta)).max(0.0);
+        let clearcoat = surface * 0.25 * self.clearcoat * schlick_scalar(CLEARCOAT_F0, wo.z);
+        let transmission = self.transmission_weight()
+            * (1.0 - fresnel_dielectric(wo.z, eta))
+            * luminance(self.base_color.map(f32::sqrt));
+        let total = diffuse + specular + clearcoat + transmission;
+        if total <= 0.0 {
+            return Lobes {
+                eta,
+                diffuse: 0.0,
+                specular: 0.0,
+                clearcoat: 0.0,
+                transmission: 0.0,
+            };
+        }
+        Lobes {
+            eta,
+            diffuse: diffuse / total,
+            specular: specular / total,
+            clearcoat: clearcoat / total,
+            transmission: transmission / total,
+        }
+    }
+
+    pub fn scatter(self, ray: Ray, hit: HitRecord, sampler: &mut Sampler) -> Scatter {
+        let frame = facing_frame(ray, hit);
+        let wo = frame.to_local(-ray.dir);
+        let lobes = self.lobes(ray, hit, wo);
+        let pick = sampler.get_1d();
+        let (r1, r2) = sampler.get_2d();
+        let wi = if pick < lobes.diffuse {
+            quasirandom_on_cosine_sphere(r1, r2)
+        } else if pick < lobes.diffuse + lobes.specular {
+            if self.smooth() {
+                return Scatter::new(
+                    self.fresnel(wo.z, lobes.eta) / lobes.specular,
+                    Ray::new(hit.point, ray.dir.reflected(frame.w)),
+                    0.0,
+                    true,
+                );
+            }
+            (-wo).reflected(Ggx::new(self.alpha()).sample_h(wo, r1, r2))
+        } else if pick < lobes.diffuse + lobes.specular + lobes.clearcoat {
+            (-wo).reflected(Gtr1::new(CLEARCOAT_ALPHA).sample_h(r1, r2))
+        } else if lobes.transmission > 0.0 {
+            if self.smooth() {
+                let Some(wi) = refract(wo, Vec3::unit_z(), lobes.eta) else {
+                    return Scatter::absorbed(hit);
+                };
+                let transmitted = self.transmission_weight()
+                    * (1.0 - fresnel_dielectric(wo.z, lobes.eta))
+                    / lobes.transmission;
+                return Scatter {
+                    eta: lobes.eta.recip(),
+                    ..Scatter::new(
+                        self.base_color.map(f32::sqrt) * transmitted,
+                        Ray::new(hit.point, frame.local(wi)),
+                        0.0,
+                        true,
+                    )
+                };
+            }
+            let h = Ggx::new(self.alpha()).sample_h(wo, r1, r2);
+            let Some(wi) = refract(wo, h, lobes.eta) else {
+                return Scatter::absorbed(hit);
+            };
+            wi
+        } else {
+            return Scatter::absorbed(hit);
+        };
+
+        let (value, pdf) = self.eval_local(wo, wi, lobes);
+        if pdf <= 0.0 {
+            return Scatter::absorbed(hit);
+        }
+        Scatter::new(
+            value / pdf,
+            Ray::new(hit.point, frame.local(wi)),
+            pdf,
+            false,
+        )
+    }
+
+    pub fn eval(self, ray: Ray, hit: HitRecord, dir: Vec3) -> (Vec3, f32) {
+        let frame = facing_frame(ray, hit);
+        let wo = frame.to_local(-ray.dir);
+        self.eval_local(wo, frame.to_local(dir), self.lobes(ray, hit, wo))
+    }
+
+    /// Every lobe that isn't a perfect mirror or window, and the density of picking `wi`
+    /// through any of them.
+    fn eval_local(self, wo: Vec3, wi: Vec3, lobes: Lobes) -> (Vec3, f32) {
+        if wo.z <= 0.0 {
+            return (Vec3::zero(), 0.0);
+        }
+        let ggx = Ggx::new(self.alpha());
+        let (mut value, mut pdf) = (Vec3::zero(), 0.0);
+        if wi.z > 0.0 {
+            let h = (wo + wi).normalized();
+            let cos_d = wi.dot(h).max(0.0);
+            if lobes.diffuse > 0.0 {
+                let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+                let retro = |cosine: f32| 1.0 + (fd90 - 1.0) * (1.0 - cosine).powi(5);
+                let diffuse = self.base_color * (FRAC_1_PI * retro(wi.z) * retro(wo.z));
+                let sheen = Vec3::one().lerp(self.tint(), SHEEN_TINT)
+                    * (self.sheen * (1.0 - cos_d).powi(5));
+                value += (diffuse + sheen) * (self.diffuse_weight() * wi.z);
+                pdf += lobes.diffuse * wi.z * FRAC_1_PI;
+            }
+            if lobes.specular > 0.0 && !self.smooth() {
+                let (specular, specular_pdf) =
+                    ggx.eval_reflection(wo, wi, |cosine| self.fresnel(cosine, lobes.eta));
+                value += specular;
+                pdf += lobes.specular * specular_pdf;
+            }
+            if lobes.clearcoat > 0.0 {
+                let coat = Gtr1::new(CLEARCOAT_ALPHA);
+                let wo_dot_h = wo.dot(h).max(1e-7);
+                let g = Ggx::new(0.25).g(wo, wi);
+                value += Vec3::one()
+                    * (0.25
+                        * self.clearcoat
+                        * schlick_scalar(CLEARCOAT_F0, wo_dot_h)
+                        * coat.d(h)
+                        * g
+                        / (4.0 * wo.z));
+                pdf += lobes.clearcoat * coat.pdf_h(h) / (4.0 * wo_dot_h);
+            }
+        } else if lobes.transmission > 0.0 && !self.smooth() {
+            let (transmitted, transmission_pdf) = ggx.eval_transmission(wo, wi, lobes.eta);
+            value += self.base_color.map(f32::sqrt) * (self.transmission_weight() * transmitted);
+            pdf += lobes.transmission * transmission_pdf;
+        }
+        (value, pdf)
+    }
+}
+
+#[inline]
+fn schlick_scalar(f0: f32, cosine: f32) -> f32 {
+    f0 + (1.0 - f0) * (1.0 - cosine.clamp(0.0, 1.0)).powi(5)
+}
+
+// Angles of arriving light the gain of a coat is measured at, with the walks per angle and the
+// most trips up and down each walk takes
+const GAIN_STEPS: usize = 16;
+const GAIN_WALKS: usize = 4096;
+const GAIN_BOUNCES: usize = 16;
+
+/// Clear dielectric layer over another material, like varnish or the top of car paint. The
+/// coat reflects its Fresnel share, and the rest refracts down to the base, picking up the
+/// coat's tint both ways. Light the underside of the coat sends back down to the base is
+/// folded into the lobe of the first trip up.
+#[derive(Debug, Clone)]
+pub struct Coat {
+    pub ior: f32,
+    pub roughness: f32,
+    /// Colour of light that went straight through one unit of thickness.
+    pub tint: Vec3,
+    pub thickness: f32,
+    /// All the light that gets out of the coat over what gets out on the first trip up, at
+    /// evenly spaced cosines of the arriving light.
+    gain: Vec<Vec3>,
+    /// The coated material with the smallest roughness it was first regularized to, built
+    /// then with its own gain so it isn't rebuilt on every hit.
+    regularized: OnceLock<(f32, Option<Box<Material>>)>,
+}
+
+impl Coat {
+    /// Measures the gain with random walks between the base and the underside of the coat,
+    /// one thread per arriving angle. Scenes build each coat once, so this only runs at load.
+    pub fn new(base: &Material, ior: f32, roughness: f32, tint: Vec3, thickness: f32) -> Coat {
+        let mut coat = Coat {
+            ior: ior.max(1.0),
+            roughness,
+            tint,
+            thickness,
+            gain: Vec::new(),
+            regularized: OnceLock::new(),
+        };
+        let hit = HitRecord::new(1.0, Vec3::zero(), Vec3::unit_z(), base);
+        let gain = (0..GAIN_STEPS)
+            .into_par_iter()
+            .map(|step| {
+                let mut sampler = Sampler::from_seed(step as u64);
+                let cosine = (step as f32 / (GAIN_STEPS - 1) as f32).max(1e-3);
+                let arriving = Vec3::new((1.0 - cosine * cosine).sqrt(), 0.0, cosine);
+                let (mut first, mut total) = (Vec3::zero(), Vec3::zero());
+                for _ in 0..GAIN_WALKS {
+                    let (mut dir, mut throughput) = (-coat.inside(arriving), Vec3::one());
+                    for bounce in 0..GAIN_BOUNCES {
+                        let scatter = base.scatter(Ray::new(-dir, dir), hit, &mut sampler);
+                        let up = scatter.ray.dir;
+                        if up.z <= 0.0 || !scatter.attenuation.x.is_finite() {
+                            break;
+                        }
+                        throughput *= scatter.attenuation * coat.absorption(up.z);
+                        let reflected = fresnel_dielectric(up.z, coat.ior.recip());
+                        let escaped = throughput * (1.0 - reflected);
+                        total += escaped;
+                        if bounce == 0 {
+                            first += escaped;
+                        }
+                        throughput *= reflected * coat.absorption(up.z);
+                        dir = Vec3::new(up.x, up.y, -up.z);
+                    }
+                }
+                let ratio = |total: f32, first: f32| {
+                    if first > 0.0 {
+                        total / first
+                    } else {
+                        1.0
+                    }
+                };
+                Vec3::new(
+                    ratio(total.x, first.x),
+                    ratio(total.y, first.y),
+                    ratio(total.z, first.z),
+                )
+            })
+            .collect();
+        coat.gain = gain;
+        coat
+    }
+
+    #[inline]
+    fn fresnel(&self, cosine: f32) -> f32 {
+        fresnel_dielectric(cosine, self.ior)
+    }
+
+    /// Colour picked up crossing the coat once at `cosine` to the normal inside it.
+    #[inline]
+    fn absorption(&self, cosine: f32) -> Vec3 {
+        let length = 0.5 * self.thickness / cosine.max(1e-4);
+        self.tint.map(|tint| tint.max(0.0).powf(length))
+    }
+
+    /// Direction inside the coat that leaves it along `w`, both pointing away from the base.
+    #[inline]
+    fn inside(&self, w: Vec3) -> Vec3 {
+        let (x, y) = (w.x / self.ior, w.y / self.ior);
+        Vec3::new(x, y, (1.0 - x * x - y * y).max(0.0).sqrt())
+    }
+
+    /// Where `w` from inside the coat goes once out, or `None` when it is reflected back.
+    #[inline]
+    fn outside(&self, w: Vec3) -> Option<Vec3> {
+        let (x, y) = (w.x * self.ior, w.y * self.ior);
+        let sin2 = x * x + y * y;
+        (sin2 < 1.0).then(|| Vec3::new(x, y, (1.0 - sin2).sqrt()))
+    }
+
+    /// Gain for light arriving from outside at `cosine`.
+    #[inline]
+    fn gain(&self, cosine: f32) -> Vec3 {
+        let x = cosine.clamp(0.0, 1.0) * (GAIN_STEPS - 1) as f32;
+        let i = (x as usize).min(GAIN_STEPS - 2);
+        let t = x - i as f32;
+        self.gain[i] * (1.0 - t) + self.gain[i + 1] * t
+    }
+
+    /// This coat over `base` with both at least `roughness` rough, or `None` when neither
+    /// changes. Only the first roughness asked for is kept, any other is built afresh.
+    fn regularized<'a>(&'a self, base: &Material, roughness: f32) -> Option<Cow<'a, Material>> {
+        let build = || {
+            let coat = match base.regularized(roughness) {
+                // The gain only depends on the base
+                Some(base) => {
+                    let coat = Coat::new(
+                        &base,
+                        self.ior,
+                        self.roughness.max(roughness),
+                        self.tint,
+                        self.thickness,
+                    );
+                    Material::Coated(Box::new(base.into_owned()), coat)
+                }
+                None if self.roughness < roughness => Material::Coated(
+                    Box::new(base.clone()),
+                    Coat {
+                        roughness,
+                        gain: self.gain.clone(),
+                        regularized: OnceLock::new(),
+                        ..*self
+                    },
+                ),
+                None => return None,
+            };
+            Some(Box::new(coat))
+        };
+        match self.regularized.get_or_init(|| (roughness, build())) {
+            (kept, material) if *kept == roughness => material.as_deref().map(Cow::Borrowed),
+            _ => build().map(|material| Cow::Owned(*material)),
+        }
+    }
+
+    pub fn scatter(
+        &self,
+        base: &Material,
+        ray: Ray,
+        hit: HitRecord,
+        sampler: &mut Sampler,
+    ) -> Scatter {
+        // Only the outside is coated
+        if ray.dir.dot(hit.normal) > 0.0 {
+            return base.scatter(ray, hit, sampler);
+        }
+        let frame = Onb::from_w(&hit.normal);
+        let wo = frame.to_local(-ray.dir);
+        let reflectance = self.fresnel(wo.z);
+
+        let wi = if sampler.get_1d() < reflectance {
+            if self.roughness <= SMOOTH {
+                return Scatter::new(
+                    Vec3::one(),
+                    Ray::new(hit.point, ray.dir.reflected(hit.normal)),
+                    0.0,
+                    true,
+                );
+            }
+            let (r1, r2) = sampler.get_2d();
+            (-wo).reflected(Ggx::new(self.roughness).sample_h(wo, r1, r2))
+        } else {
+            let wo_inside = self.inside(wo);
+            let inner = Ray {
+                dir: frame.local(-wo_inside),
+                ..ray
+            };
+            let scatter = base.scatter(inner, hit, sampler);
+            if scatter.attenuation == Vec3::zero() {
+                return scatter;
+            }
+            let wi_inside = frame.to_local(scatter.ray.dir);
+            if wi_inside.z <= 0.0 {
+                // Into the base, which only the way down through the coat can see
+                return Scatter {
+                    attenuation: scatter.attenuation * self.absorption(wo_inside.z),
+                    pdf: scatter.pdf * (1.0 - reflectance),
+                    ..scatter
+                };
+            }
+            let Some(wi) = self.outside(wi_inside) else {
+                return Scatter::absorbed(hit);
+            };
+            if scatter.specular {
+                let through = (1.0 - self.fresnel(wi.z))
+                    * self.absorption(wo_inside.z)
+                    * self.absorption(wi_inside.z)
+                    * self.gain(wo.z);
+                return Scatter {
+                    attenuation: scatter.attenuation * through,
+                    ray: Ray::new(hit.point, frame.local(wi)),
+                    ..scatter
+                };
+            }
+            wi
+        };
+
+        let (value, pdf) = self.eval_local(base, ray, hit, &frame, wo, wi);
+        if pdf <= 0.0 {
+            return Scatter::absorbed(hit);
+        }
+        Scatter::new(
+            value / pdf,
+            Ray::new(hit.point, frame.local(wi)),
+            pdf,
+            false,
+        )
+    }
+
+    pub fn eval(&self, base: &Material, ray: Ray, hit: HitRecord, dir: Vec3) -> (Vec3, f32) {
+        if ray.dir.dot(hit.normal) > 0.0 {
+            return base.eval(ray, hit, dir);
+        }
+        let frame = Onb::from_w(&hit.normal);
+        let (wo, wi) = (frame.to_local(-ray.dir), frame.to_local(dir));
+        self.eval_local(base, ray, hit, &frame, wo, wi)
+    }
+
+    /// Rough coat reflection plus the base seen through the coat, and the density of either
+    /// being sampled. The base's own density is carried out of the coat by the change in solid
+    /// angle across the interface.
+    fn eval_local(
+        &self,
+        base: &Material,
+        ray: Ray,
+        hit: HitRecord,
+        frame: &Onb,
+        wo: Vec3,
+        wi: Vec3,
+    ) -> (Vec3, f32) {
+        if wo.z <= 0.0 {
+            return (Vec3::zero(), 0.0);
+        }
+        let reflectance = self.fresnel(wo.z);
+        let wo_inside = self.inside(wo);
+        let inner = Ray {
+            dir: frame.local(-wo_inside),
+            ..ray
+        };
+        if wi.z <= 0.0 {
+            let (value, pdf) = base.eval(inner, hit, frame.local(wi));
+            return (
+                value * self.absorption(wo_inside.z) * (1.0 - reflectance),
+                pdf * (1.0 - reflectance),
+            );
+        }
+
+        let (mut value, mut pdf) = (Vec3::zero(), 0.0);
+        if self.roughness > SMOOTH {
+            let (coat, coat_pdf) = Ggx::new(self.roughness)
+                .eval_reflection(wo, wi, |cosine| Vec3::one() * self.fresnel(cosine));
+            value += coat;
+            pdf += reflectance * coat_pdf;
+        }
+        let wi_inside = self.inside(wi);
+        let (base_value, base_pdf) = base.eval(inner, hit, frame.local(wi_inside));
+        let jacobian = wi.z / (self.ior * self.ior * wi_inside.z.max(1e-7));
+        let through = (1.0 - reflectance) * (1.0 - self.fresnel(wi.z)) * jacobian;
+        value += base_value
+            * self.absorption(wo_inside.z)
+            * self.absorption(wi_inside.z)
+            * self.gain(wo.z)
+            * through;
+        pdf += (1.0 - reflectance) * base_pdf * jacobian;
+        (value, pdf)
+    }
+}
+
+/// Phase function of a participating medium. `g` above zero scatters light forwards.
+#[derive(Debug, Copy, Clone)]
+pub struct HenyeyGreenstein {
+    pub albedo: Vec3,
+    pub g: f32,
+}
+
+impl HenyeyGreenstein {
+    #[inline]
+    fn phase(self, cosine: f32) -> f32 {
+        let denom = 1.0 + self.g * self.g - 2.0 * self.g * cosine;
+        FRAC_1_PI * 0.25 * (1.0 - self.g * self.g) / (denom * denom.max(1e-7).sqrt())
+    }
+
+    pub fn scatter(self, ray: Ray, hit: HitRecord, r1: f32, r2: f32) -> Scatter {
+        let cosine = if self.g.abs() < 1e-3 {
+            1.0 - 2.0 * r2
+        } else {
+            let sq = (1.0 - self.g * self.g) / (1.0 - self.g + 2.0 * self.g * r2);
+            (1.0 + self.g * self.g - sq * sq) / (2.0 * self.g)
+        }
+        .clamp(-1.0, 1.0);
+        let sin_theta = (1.0 - cosine * cosine).max(0.0).sqrt();
+        let phi = 2.0 * PI * r1;
+        let direction = Onb::from_w(&ray.dir).local(Vec3::new(
+            phi.cos() * sin_theta,
+            phi.sin() * sin_theta,
+            cosine,
+        ));
+        Scatter::new(
+            self.albedo,
+            Ray::new(hit.point, direction),
+            self.phase(cosine),
+            false,
+        )
+    }
+
+    pub fn eval(self, ray: Ray, _: HitRecord, dir: Vec3) -> (Vec3, f32) {
+        let pdf = self.phase(ray.dir.dot(dir));
+        (self.albedo * pdf, pdf)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Emissive {
+    pub radiance: Vec3,
+    pub strength: f32,
+    pub two_sided: bool,
+}
+
+impl Emissive {
+    /// Light given off towards the origin of `ray`. One-sided emitters are dark from behind.
+    #[inline]
+    pub fn emitted(self, ray: Ray, hit: HitRecord) -> Vec3 {
+        if self.two_sided || ray.dir.dot(hit.normal) < 0.0 {
+            self.radiance * self.strength
+        } else {
+            Vec3::zero()
+        }
+    }
+
+    /// Direction light leaves in, cosine weighted about `normal` and split between both sides
+    /// when two-sided, with its solid angle density.
+    pub fn sample(self, normal: Vec3, r1: f32, r2: f32) -> (Vec3, f32) {
+        let (side, r1) = match (self.two_sided, r1 < 0.5) {
+            (false, _) => (normal, r1),
+            (true, true) => (normal, r1 * 2.0),
+            (true, false) => (-normal, r1 * 2.0 - 1.0),
+        };
+        let dir = quasirandom_on_hemisphere(side, r1, r2);
+        (dir, self.pdf(normal, dir))
+    }
+
+    /// Solid angle density of `sample` choosing `dir`.
+    #[inline]
+    pub fn pdf(self, normal: Vec3, dir: Vec3) -> f32 {
+        let cosine = normal.dot(dir);
+        if self.two_sided {
+            cosine.abs() * FRAC_1_PI * 0.5
+        } else {
+            cosine.max(0.0) * FRAC_1_PI
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Material {
     Dielectric(Dielectric),
     Lambertian(Lambertian),
     Metal(Metal),
     Glossy(Glossy),
+    Principled(Principled),
+    HenyeyGreenstein(HenyeyGreenstein),
+    Emissive(Emissive),
+    Emitting(Box<Material>, Emissive),
+    Coated(Box<Material>, Coat),
 }
 
 impl Material {
@@ -158,36 +1063,305 @@ impl Material {
         })
     }
 
+    /// Metal reflecting `albedo` head on, with a complex index fitted to it.
     pub fn metal(albedo: (f32, f32, f32), roughness: f32) -> Material {
+        let albedo = Vec3::new(albedo.0, albedo.1, albedo.2);
+        let (eta, k) = conductor_index(albedo);
         Material::Metal(Metal {
-            albedo: Vec3::new(albedo.0, albedo.1, albedo.2),
-            roughness,
+            albedo,
+            ..Metal::new(eta, k, roughness)
         })
     }
 
-    pub fn dielectric(albedo: (f32, f32, f32), refractive_index: f32, roughness: f32) -> Material {
+    /// Metal with the complex index `eta + i k`.
+    pub fn conductor(eta: (f32, f32, f32), k: (f32, f32, f32), roughness: f32) -> Material {
+        Material::Metal(Metal::new(
+            Vec3::new(eta.0, eta.1, eta.2),
+            Vec3::new(k.0, k.1, k.2),
+            roughness,
+        ))
+    }
+
+    pub fn dielectric(
+        albedo: (f32, f32, f32),
+        refractive_index: f32,
+        roughness: f32,
+        abbe: f32,
+    ) -> Material {
         Material::Dielectric(Dielectric {
             albedo: Vec3::new(albedo.0, albedo.1, albedo.2),
             refractive_index,
             roughness,
+            abbe,
         })
     }
 
-    pub fn scatter(self, ray: Ray, hit: HitRecord, r1: f32, r2: f32) -> Scatter {
-        match hit.material {
-            Material::Dielectric(d) => d.scatter(ray, hit, r1, r2),
-            Material::Lambertian(l) => l.scatter(ray, hit, r1, r2),
-            Material::Metal(m) => m.scatter(ray, hit, r1, r2),
-            Material::Glossy(g) => g.scatter(ray, hit, r1, r2),
+    #[allow(clippy::too_many_arguments)]
+    pub fn principled(
+        base_color: (f32, f32, f32),
+        metallic: f32,
+        roughness: f32,
+        specular: f32,
+        specular_tint: f32,
+        sheen: f32,
+        clearcoat: f32,
+        transmission: f32,
+        ior: f32,
+    ) -> Material {
+        Material::Principled(Principled {
+            base_color: Vec3::new(base_color.0, base_color.1, base_color.2),
+            metallic: metallic.clamp(0.0, 1.0),
+            roughness: roughness.clamp(0.0, 1.0),
+            specular: specular.max(0.0),
+            specular_tint: specular_tint.clamp(0.0, 1.0),
+            sheen: sheen.max(0.0),
+            clearcoat: clearcoat.max(0.0),
+            transmission: transmission.clamp(0.0, 1.0),
+            ior: ior.max(1.0),
+        })
+    }
+
+    pub fn henyey_greenstein(albedo: (f32, f32, f32), g: f32) -> Material {
+        Material::HenyeyGreenstein(HenyeyGreenstein {
+            albedo: Vec3::new(albedo.0, albedo.1, albedo.2),
+            g: g.clamp(-0.99, 0.99),
+        })
+    }
+
+    pub fn emissive(radiance: (f32, f32, f32), strength: f32, two_sided: bool) -> Material {
+        Material::Emissive(Emissive {
+            radiance: Vec3::new(radiance.0, radiance.1, radiance.2),
+            strength,
+            two_sided,
+        })
+    }
+
+    /// Puts a dielectric coat over `base`.
+    pub fn coated(
+        base: Material,
+        ior: f32,
+        roughness: f32,
+        tint: (f32, f32, f32),
+        thickness: f32,
+    ) -> Material {
+        let tint = Vec3::new(tint.0, tint.1, tint.2);
+        let coat = Coat::new(&base, ior, roughness, tint, thickness.max(0.0));
+        Material::Coated(Box::new(base), coat)
+    }
+
+    /// Adds an emission term on top of the reflectance of `base`.
+    pub fn emitting(
+        base: Material,
+        radiance: (f32, f32, f32),
+        strength: f32,
+        two_sided: bool,
+    ) -> Material {
+        Material::Emitting(
+            Box::new(base),
+            Emissive {
+                radiance: Vec3::new(radiance.0, radiance.1, radiance.2),
+                strength,
+                two_sided,
+            },
+        )
+    }
+
+    pub fn scatter(&self, ray: Ray, hit: HitRecord, sampler: &mut Sampler) -> Scatter {
+        let scatter = match self {
+            Material::Dielectric(d) => d.scatter(ray, hit, sampler),
+            Material::Lambertian(l) => {
+                let (r1, r2) = sampler.get_2d();
+                l.scatter(ray, hit, r1, r2)
+            }
+            Material::Metal(m) => {
+                let (r1, r2) = sampler.get_2d();
+                m.scatter(ray, hit, r1, r2)
+            }
+            Material::Glossy(g) => {
+                let (r1, r2) = sampler.get_2d();
+                g.scatter(ray, hit, r1, r2)
+            }
+            Material::Principled(p) => p.scatter(ray, hit, sampler),
+            Material::HenyeyGreenstein(h) => {
+                let (r1, r2) = sampler.get_2d();
+                h.scatter(ray, hit, r1, r2)
+            }
+            Material::Emissive(_) => Scatter::absorbed(hit),
+            Material::Emitting(base, _) => base.scatter(ray, hit, sampler),
+            Material::Coated(base, coat) => coat.scatter(base, ray, hit, sampler),
+        };
+        // Light leaves at the moment it arrived, so moving objects are seen where they were.
+        // Rough lobes spread a pixel much wider than differentials can follow, so only
+        // specular bounces keep them.
+        let differentials = if scatter.specular {
+            specular_differentials(ray, hit, scatter)
+        } else {
+            None
+        };
+        Scatter {
+            ray: scatter
+                .ray
+                .with_time(ray.time)
+                .with_differentials(differentials),
+            ..scatter
+        }
+    }
+
+    /// BSDF times cosine for light arriving from `dir`, and the density of `scatter` choosing it.
+    /// Perfectly specular lobes can't be evaluated and report zero.
+    pub fn eval(&self, ray: Ray, hit: HitRecord, dir: Vec3) -> (Vec3, f32) {
+        match self {
+            Material::Dielectric(d) => d.eval(ray, hit, dir),
+            Material::Emissive(_) => (Vec3::zero(), 0.0),
+            Material::Lambertian(l) => l.eval(ray, hit, dir),
+            Material::Metal(m) => m.eval(ray, hit, dir),
+            Material::Glossy(g) => g.eval(ray, hit, dir),
+            Material::Principled(p) => p.eval(ray, hit, dir),
+            Material::HenyeyGreenstein(h) => h.eval(ray, hit, dir),
+            Material::Emitting(base, _) => base.eval(ray, hit, dir),
+            Material::Coated(base, coat) => coat.eval(base, ray, hit, dir),
         }
     }
 
-    pub fn color(self) -> Vec3 {
+    /// Version with metal and glossy lobes no sharper than `roughness`, or `None` when that
+    /// changes nothing. Coats hand out the one they keep rather than a copy, and lights their
+    /// base, as only scattering reads the result.
+    pub fn regularized(&self, roughness: f32) -> Option<Cow<'_, Material>> {
+        match self {
+            Material::Metal(m) if m.roughness < roughness => {
+                Some(Cow::Owned(Material::Metal(Metal { roughness, ..*m })))
+            }
+            Material::Glossy(g) if g.roughness < roughness => {
+                Some(Cow::Owned(Material::Glossy(Glossy { roughness, ..*g })))
+            }
+            Material::Principled(p) if p.alpha() < roughness => {
+                Some(Cow::Owned(Material::Principled(Principled {
+                    roughness: roughness.sqrt(),
+                    ..*p
+                })))
+            }
+            Material::Emitting(base, _) => base.regularized(roughness),
+            Material::Coated(base, coat) => coat.regularized(base, roughness),
+            _ => None,
+        }
+    }
+
+    /// Whether the surface reflects or transmits any light at all.
+    #[inline]
+    pub fn scatters(&self) -> bool {
+        !matches!(self, Material::Emissive(_))
+    }
+
+    /// Whether every lobe is a perfect mirror or window, so light sampling can't reach any of
+    /// them. Surfaces that only sometimes pick a delta lobe still have the rest to sample.
+    #[inline]
+    pub fn is_delta(&self) -> bool {
+        match self {
+            Material::Metal(m) => m.roughness <= SMOOTH,
+            Material::Dielectric(d) => d.roughness <= SMOOTH,
+            Material::Principled(p) => {
+                p.smooth() && p.diffuse_weight() <= 0.0 && p.clearcoat <= 0.0
+            }
+            Material::Emitting(base, _) => base.is_delta(),
+            Material::Coated(base, coat) => coat.roughness <= SMOOTH && base.is_delta(),
+            _ => false,
+        }
+    }
+
+    /// Whether hits are scattering events inside a participating medium rather than a surface.
+    #[inline]
+    pub fn is_medium(&self) -> bool {
+        matches!(self, Material::HenyeyGreenstein(_))
+    }
+
+    #[inline]
+    pub fn is_emissive(&self) -> bool {
+        matches!(self, Material::Emissive(_) | Material::Emitting(..))
+    }
+
+    #[inline]
+    pub fn emitter(&self) -> Option<Emissive> {
+        match self {
+            Material::Emissive(e) | Material::Emitting(_, e) => Some(*e),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn emitted(&self, ray: Ray, hit: HitRecord) -> Vec3 {
+        match self {
+            Material::Emissive(e) | Material::Emitting(_, e) => e.emitted(ray, hit),
+            _ => Vec3::zero(),
+        }
+    }
+
+    pub fn color(&self) -> Vec3 {
         match self {
             Material::Dielectric(d) => d.albedo,
             Material::Lambertian(l) => l.albedo,
             Material::Metal(m) => m.albedo,
             Material::Glossy(g) => g.albedo,
+            Material::Principled(p) => p.base_color,
+            Material::HenyeyGreenstein(h) => h.albedo,
+            Material::Emissive(e) => e.radiance,
+            Material::Emitting(base, _) => base.color(),
+            Material::Coated(base, coat) => base.color() * coat.tint,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regularizing_only_roughens_sharp_lobes() {
+        match Material::metal((0.9, 0.9, 0.9), 0.05)
+            .regularized(0.3)
+            .as_deref()
+        {
+            Some(Material::Metal(metal)) => assert_eq!(metal.roughness, 0.3),
+            other => panic!("{other:?}"),
+        }
+        match Material::glossy((0.5, 0.5, 0.5), 1.0, 0.0)
+            .regularized(0.3)
+            .as_deref()
+        {
+            Some(Material::Glossy(glossy)) => assert_eq!(glossy.roughness, 0.3),
+            other => panic!("{other:?}"),
+        }
+        assert!(Material::metal((0.9, 0.9, 0.9), 0.5)
+            .regularized(0.3)
+            .is_none());
+        assert!(Material::lambertian((0.5, 0.5, 0.5))
+            .regularized(0.3)
+            .is_none());
+        assert!(Material::dielectric((1.0, 1.0, 1.0), 1.5, 0.0, 0.0)
+            .regularized(0.3)
+            .is_none());
+    }
+
+    #[test]
+    fn coats_lend_the_regularized_version_they_keep() {
+        let coated = Material::coated(
+            Material::lambertian((0.5, 0.5, 0.5)),
+            1.5,
+            0.0,
+            (1.0, 1.0, 1.0),
+            0.0,
+        );
+        let light = Material::emitting(coated, (1.0, 1.0, 1.0), 1.0, false);
+        let (Some(Cow::Borrowed(first)), Some(Cow::Borrowed(second))) =
+            (light.regularized(0.3), light.regularized(0.3))
+        else {
+            panic!("regularized coat was copied");
+        };
+        assert!(std::ptr::eq(first, second));
+        match first {
+            Material::Coated(_, coat) => assert_eq!(coat.roughness, 0.3),
+            other => panic!("{other:?}"),
         }
+        // Any other roughness is built afresh
+        assert!(matches!(light.regularized(0.2), Some(Cow::Owned(_))));
     }
 }
@@ -0,0 +1,177 @@
+use rayon::prelude::*;
+use ultraviolet::Vec3;
+
+use crate::{
+    camera::Camera,
+    environment::Environment,
+    film::luminance,
+    random::{Distribution, Sampler},
+    render::{ray_color, Fireflies},
+    spectrum::Wavelengths,
+    tracer::{
+        bvh::{Bvh, TraversalStats},
+        lights::Lights,
+    },
+};
+
+// Independent paths traced each pass to find the image brightness and seed the chains
+const BOOTSTRAP: usize = 100_000;
+// Chains run each pass, fixed so the image doesn't depend on the thread count
+const CHAINS: usize = 256;
+// Spread of a small step in primary sample space
+const SIGMA: f32 = 0.01;
+// Chance of a mutation drawing every random number afresh
+const LARGE_STEP_PROBABILITY: f32 = 0.3;
+// Keeps the acceptance decisions apart from the streams behind the paths
+const CHAIN_STREAM: u64 = 0xd1b5_4a32_d192_ed03;
+
+/// Primary sample space Metropolis light transport over `ray_color`. Chains wander the random
+/// numbers a camera path is built from, so once a rare path that carries light is found its
+/// neighbours get explored too. Every chain leaves its light on the film as splats.
+pub struct Metropolis<'a> {
+    pub world: &'a Bvh,
+    pub lights: &'a Lights,
+    pub camera: &'a Camera,
+    pub image: &'a Option<Environment>,
+    pub width: usize,
+    pub height: usize,
+    pub max_bounce: u32,
+    pub fireflies: Fireflies,
+    pub spectral: bool,
+}
+
+impl Metropolis<'_> {
+    /// Runs about `mutations` mutations spread over every chain of one pass. Returns light per
+    /// pixel, to be scaled by pixels per mutation, the mutations made and the BVH work done.
+    pub fn render(&self, seed: u64, pass: u32, mutations: u64) -> (Vec<Vec3>, u64, TraversalStats) {
+        let (weights, work): (Vec<f32>, Vec<TraversalStats>) = (0..BOOTSTRAP)
+            .into_par_iter()
+            .map(|index| {
+                let start = TraversalStats::current();
+                let (_, color) = self.path(&mut self.sampler(seed, pass, index));
+                (luminance(color).max(0.0), TraversalStats::current() - start)
+            })
+            .unzip();
+        let mut traversal = work
+            .into_iter()
+            .fold(TraversalStats::zero(), |total, work| total + work);
+        let bootstrap = Distribution::new(weights);
+        let brightness = bootstrap.total() / BOOTSTRAP as f32;
+        let mut splats = vec![Vec3::zero(); self.width * self.height];
+        if brightness <= 0.0 {
+            return (splats, 0, traversal);
+        }
+
+        let per_chain = mutations.div_ceil(CHAINS as u64);
+        let chains = (0..CHAINS).collect::<Vec<usize>>();
+        // A batch at a time bounds the memory, and adding the chains in order keeps the sums exact
+        chains
+            .chunks(rayon::current_num_threads())
+            .for_each(|batch| {
+                batch
+                    .par_iter()
+                    .map(|&chain| self.chain(&bootstrap, seed, pass, chain, per_chain))
+                    .collect::<Vec<(Vec<Vec3>, TraversalStats)>>()
+                    .into_iter()
+                    .for_each(|(chain_splats, work)| {
+                        splats
+                            .iter_mut()
+                            .zip(chain_splats)
+                            .for_each(|(total, splat)| *total += splat * brightness);
+                        traversal = traversal + work;
+                    });
+            });
+        (splats, per_chain * CHAINS as u64, traversal)
+    }
+
+    /// Runs one chain from a bootstrap path picked by brightness. Each mutation leaves light
+    /// at both the current and the proposed path, weighted by the chance of accepting it.
+    fn chain(
+        &self,
+        bootstrap: &Distribution,
+        seed: u64,
+        pass: u32,
+        chain: usize,
+        mutations: u64,
+    ) -> (Vec<Vec3>, TraversalStats) {
+        let start = TraversalStats::current();
+        let decisions = &mut Sampler::new(seed ^ CHAIN_STREAM, pass, chain);
+        let (index, _) = bootstrap.sample(decisions.get_1d());
+        // Built from the same stream as in the bootstrap, so it replays the same path
+        let sampler = &mut self.sampler(seed, pass, index);
+        let (mut pixel, mut color) = self.path(sampler);
+        let mut current = luminance(color);
+        let mut splats = vec![Vec3::zero(); self.width * self.height];
+        for _ in 0..mutations {
+            sampler.mutate();
+            let (proposed_pixel, proposed_color) = self.path(sampler);
+            let proposed = luminance(proposed_color).max(0.0);
+            let accept = if current > 0.0 {
+                (proposed / current).min(1.0)
+            } else {
+                1.0
+            };
+            if proposed > 0.0 {
+                splats[proposed_pixel] += proposed_color * (accept / proposed);
+            }
+            if current > 0.0 {
+                splats[pixel] += color * ((1.0 - accept) / current);
+            }
+            if decisions.get_1d() < accept {
+                (pixel, color, current) = (proposed_pixel, proposed_color, proposed);
+                sampler.accept();
+            } else {
+                sampler.reject();
+            }
+        }
+        (splats, TraversalStats::current() - start)
+    }
+
+    #[inline]
+    fn sampler(&self, seed: u64, pass: u32, index: usize) -> Sampler {
+        Sampler::replayable(seed, pass, index, SIGMA, LARGE_STEP_PROBABILITY)
+    }
+
+    /// Camera path through the film position given by the first two random numbers, as the
+    /// buffer index it lands on and its RGB radiance.
+    fn path(&self, sampler: &mut Sampler) -> (usize, Vec3) {
+        let (u, v) = sampler.get_2d();
+        let (fx, fy) = (u * self.width as f32, v * self.height as f32);
+        let x = (fx as usize).min(self.width - 1);
+        let y = (fy as usize).min(self.height - 1);
+        let time = self.camera.sample_time(sampler);
+        let ray = self
+            .camera
+            .at(time)
+            .gen_ray(
+                self.width,
+                self.height,
+                x as f32,
+                y as f32,
+                fy.fract(),
+                fx.fract(),
+                sampler,
+            )
+            .with_time(time);
+        let ray = if self.spectral {
+            ray.with_wavelengths(Some(Wavelengths::sample(sampler.get_1d())))
+        } else {
+            ray
+        };
+        let color = ray_color(
+            ray,
+            self.world,
+            self.lights,
+            self.max_bounce,
+            self.image,
+            self.fireflies,
+            None,
+            sampler,
+        );
+        let mut color = ray.wavelengths.map_or(color, |w| w.to_rgb(color));
+        if !(color.x.is_finite() && color.y.is_finite() && color.z.is_finite()) {
+            color = Vec3::zero();
+        }
+        ((self.height - 1 - y) * self.width + x, color)
+    }
+}
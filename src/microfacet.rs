@@ -0,0 +1,416 @@
+use std::{
+    f32::consts::{FRAC_1_PI, PI},
+    sync::OnceLock,
+};
+
+use ultraviolet::Vec3;
+
+// Steps along cos(wo) and alpha of the GGX albedo table, and samples per entry along each axis
+const ALBEDO_STEPS: usize = 32;
+const ALBEDO_SAMPLES: usize = 16;
+
+/// Trowbridge-Reitz (GGX) distribution of microfacet normals.
+/// Vectors are in the local shading frame, with the surface normal along +z.
+#[derive(Debug, Copy, Clone)]
+pub struct Ggx {
+    pub alpha: f32,
+}
+
+impl Ggx {
+    pub fn new(alpha: f32) -> Ggx {
+        Ggx {
+            alpha: alpha.max(1e-4),
+        }
+    }
+
+    #[inline]
+    pub fn d(&self, h: Vec3) -> f32 {
+        if h.z <= 0.0 {
+            return 0.0;
+        }
+        let a2 = self.alpha * self.alpha;
+        // Written without `1 - cos^2`, which loses everything near the pole of a narrow lobe
+        let denom = h.z * h.z * a2 + h.x * h.x + h.y * h.y;
+        a2 / (PI * denom * denom)
+    }
+
+    #[inline]
+    pub fn lambda(&self, v: Vec3) -> f32 {
+        let cos2 = v.z * v.z;
+        if cos2 <= 0.0 {
+            return f32::INFINITY;
+        }
+        let tan2 = (1.0 - cos2).max(0.0) / cos2;
+        0.5 * ((1.0 + self.alpha * self.alpha * tan2).sqrt() - 1.0)
+    }
+
+    #[inline]
+    pub fn g1(&self, v: Vec3) -> f32 {
+        (1.0 + self.lambda(v)).recip()
+    }
+
+    /// Height-correlated Smith masking-shadowing.
+    #[inline]
+    pub fn g(&self, wo: Vec3, wi: Vec3) -> f32 {
+        (1.0 + self.lambda(wo) + self.lambda(wi)).recip()
+    }
+
+    /// Samples a microfacet normal among those visible from `wo`, proportionally to
+    /// `G1(wo) * max(0, wo . h) * D(h) / cos(wo)`, after Heitz's 2018 method. Facets turned away
+    /// from `wo` are never picked, which keeps weights bounded at grazing angles.
+    #[inline]
+    pub fn sample_h(&self, wo: Vec3, r1: f32, r2: f32) -> Vec3 {
+        // Stretch the view so the lobe becomes a hemisphere
+        let view = Vec3::new(self.alpha * wo.x, self.alpha * wo.y, wo.z).normalized();
+        let len2 = view.x * view.x + view.y * view.y;
+        let t1 = if len2 > 0.0 {
+            Vec3::new(-view.y, view.x, 0.0) / len2.sqrt()
+        } else {
+            Vec3::unit_x()
+        };
+        let t2 = view.cross(t1);
+
+        // Uniform point on the projected disk, squeezed onto the visible half
+        let radius = r1.sqrt();
+        let phi = 2.0 * PI * r2;
+        let p1 = radius * phi.cos();
+        let blend = 0.5 * (1.0 + view.z);
+        let p2 = (1.0 - blend) * (1.0 - p1 * p1).max(0.0).sqrt() + blend * radius * phi.sin();
+        let n = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * view;
+
+        Vec3::new(self.alpha * n.x, self.alpha * n.y, n.z.max(1e-6)).normalized()
+    }
+
+    /// Density of `sample_h` returning `h` for `wo`.
+    #[inline]
+    pub fn pdf_h(&self, wo: Vec3, h: Vec3) -> f32 {
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * wo.dot(h).max(0.0) * self.d(h) / wo.z
+    }
+
+    /// Reflection lobe `f * cos(wi)` and the solid angle density of sampling `wi` from `wo`,
+    /// with `fresnel` evaluated at the half vector.
+    #[inline]
+    pub fn eval_reflection(
+        &self,
+        wo: Vec3,
+        wi: Vec3,
+        fresnel: impl Fn(f32) -> Vec3,
+    ) -> (Vec3, f32) {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return (Vec3::zero(), 0.0);
+        }
+        let h = (wo + wi).normalized();
+        let wo_dot_h = wo.dot(h).max(1e-7);
+        let d = self.d(h);
+        let value = fresnel(wo_dot_h) * (d * self.g(wo, wi) / (4.0 * wo.z));
+        let pdf = self.pdf_h(wo, h) / (4.0 * wo_dot_h);
+        (value, pdf)
+    }
+
+    /// GGX transmission through a rough interface, after Walter et al. `wo` is above the surface
+    /// and `wi` below, and `eta` is the index below over the index above. Returns the lobe
+    /// `f * |cos(wi)|`, Fresnel included, and the solid angle density of sampling `wi` by
+    /// refracting `wo` through a normal from `sample_h`. Like the smooth glass, the lobe leaves
+    /// out the `eta^2` change in radiance, which cancels for light that leaves again.
+    #[inline]
+    pub fn eval_transmission(&self, wo: Vec3, wi: Vec3, eta: f32) -> (f32, f32) {
+        if wo.z <= 0.0 || wi.z >= 0.0 {
+            return (0.0, 0.0);
+        }
+        let h = transmission_half(wo, wi, eta);
+        let (wo_dot_h, wi_dot_h) = (wo.dot(h), wi.dot(h));
+        // Both sides of the microfacet have to face their own side of the surface
+        if wo_dot_h <= 0.0 || wi_dot_h >= 0.0 {
+            return (0.0, 0.0);
+        }
+        let denom = (wo_dot_h + eta * wi_dot_h).powi(2);
+        let d = self.d(h);
+        let transmitted = 1.0 - fresnel_dielectric(wo_dot_h, eta);
+        let eta2 = eta * eta;
+        let value =
+            transmitted * d * self.g(wo, wi) * wi_dot_h.abs() * wo_dot_h * eta2 / (wo.z * denom);
+        let pdf = self.pdf_h(wo, h) * eta2 * wi_dot_h.abs() / denom;
+        (value, pdf)
+    }
+
+    /// Share of light reaching `wo` that leaves after one bounce off facets that lose nothing.
+    /// The rest bounces between facets, which single scattering leaves out.
+    #[inline]
+    pub fn albedo(&self, cosine: f32) -> f32 {
+        albedo_table().directional(cosine, self.alpha)
+    }
+
+    /// Cosine weighted average of `albedo` over the hemisphere.
+    #[inline]
+    pub fn average_albedo(&self) -> f32 {
+        albedo_table().average(self.alpha)
+    }
+
+    /// Kulla and Conty's lobe for light that scatters between facets more than once, as
+    /// `f * cos(wi)`. `fresnel_average` is the cosine weighted average of the Fresnel term.
+    #[inline]
+    pub fn eval_multiple(&self, wo: Vec3, wi: Vec3, fresnel_average: Vec3) -> Vec3 {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return Vec3::zero();
+        }
+        let average = self.average_albedo();
+        let missing = (1.0 - self.albedo(wo.z)) * (1.0 - self.albedo(wi.z));
+        // Each extra bounce keeps `fresnel_average` of the light, summed as a geometric series
+        let fresnel = fresnel_average * fresnel_average * average
+            / (Vec3::one() - fresnel_average * (1.0 - average));
+        fresnel * (missing * FRAC_1_PI * wi.z / (1.0 - average).max(1e-4))
+    }
+}
+
+/// Generalized Trowbridge-Reitz with `gamma = 1`, the long tailed lobe of Disney's clearcoat.
+#[derive(Debug, Copy, Clone)]
+pub struct Gtr1 {
+    pub alpha: f32,
+}
+
+impl Gtr1 {
+    pub fn new(alpha: f32) -> Gtr1 {
+        Gtr1 {
+            alpha: alpha.clamp(1e-3, 0.999),
+        }
+    }
+
+    #[inline]
+    pub fn d(&self, h: Vec3) -> f32 {
+        if h.z <= 0.0 {
+            return 0.0;
+        }
+        let a2 = self.alpha * self.alpha;
+        (a2 - 1.0) / (PI * a2.ln() * (h.z * h.z * a2 + h.x * h.x + h.y * h.y))
+    }
+
+    /// Samples a microfacet normal proportionally to `D(h) * cos(h)`.
+    #[inline]
+    pub fn sample_h(&self, r1: f32, r2: f32) -> Vec3 {
+        let a2 = self.alpha * self.alpha;
+        let phi = 2.0 * PI * r1;
+        let cos_theta = ((1.0 - a2.powf(1.0 - r2)) / (1.0 - a2))
+            .clamp(0.0, 1.0)
+            .sqrt();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta)
+    }
+
+    #[inline]
+    pub fn pdf_h(&self, h: Vec3) -> f32 {
+        self.d(h) * h.z.abs()
+    }
+}
+
+/// Directional albedo of GGX with no Fresnel loss, tabulated over cos(wo) and alpha, with its
+/// cosine weighted hemispherical average per alpha.
+struct AlbedoTable {
+    directional: Vec<f32>,
+    average: Vec<f32>,
+}
+
+fn albedo_table() -> &'static AlbedoTable {
+    static TABLE: OnceLock<AlbedoTable> = OnceLock::new();
+    TABLE.get_or_init(AlbedoTable::build)
+}
+
+impl AlbedoTable {
+    /// Integrates each entry with stratified visible normal samples, which only leave the
+    /// `G / G1` ratio to average.
+    fn build() -> AlbedoTable {
+        let step = |i: usize| i as f32 / (ALBEDO_STEPS - 1) as f32;
+        let mut directional = Vec::with_capacity(ALBEDO_STEPS * ALBEDO_STEPS);
+        for a in 0..ALBEDO_STEPS {
+            let ggx = Ggx::new(step(a));
+            for c in 0..ALBEDO_STEPS {
+                let cosine = step(c).max(1e-3);
+                let wo = Vec3::new((1.0 - cosine * cosine).sqrt(), 0.0, cosine);
+                let mut total = 0.0;
+                for i in 0..ALBEDO_SAMPLES * ALBEDO_SAMPLES {
+                    let r1 = ((i / ALBEDO_SAMPLES) as f32 + 0.5) / ALBEDO_SAMPLES as f32;
+                    let r2 = ((i % ALBEDO_SAMPLES) as f32 + 0.5) / ALBEDO_SAMPLES as f32;
+                    let wi = (-wo).reflected(ggx.sample_h(wo, r1, r2));
+                    if wi.z > 0.0 {
+                        total += ggx.g(wo, wi) / ggx.g1(wo);
+                    }
+                }
+                directional.push(total / (ALBEDO_SAMPLES * ALBEDO_SAMPLES) as f32);
+            }
+        }
+        let average = directional
+            .chunks(ALBEDO_STEPS)
+            .map(|row| {
+                // Trapezoid rule for 2 * integral of E(cos) * cos
+                (1..ALBEDO_STEPS)
+                    .map(|c| {
+                        let (c0, c1) = (step(c - 1), step(c));
+                        (row[c - 1] * c0 + row[c] * c1) * (c1 - c0)
+                    })
+                    .sum::<f32>()
+            })
+            .collect();
+        AlbedoTable {
+            directional,
+            average,
+        }
+    }
+
+    /// Position of `x` between table steps, as the lower step and the blend to the next.
+    #[inline]
+    fn locate(x: f32) -> (usize, f32) {
+        let x = x.clamp(0.0, 1.0) * (ALBEDO_STEPS - 1) as f32;
+        let i = (x as usize).min(ALBEDO_STEPS - 2);
+        (i, x - i as f32)
+    }
+
+    fn directional(&self, cosine: f32, alpha: f32) -> f32 {
+        let (c, tc) = AlbedoTable::locate(cosine);
+        let (a, ta) = AlbedoTable::locate(alpha);
+        let at = |a: usize, c: usize| self.directional[a * ALBEDO_STEPS + c];
+        let low = at(a, c) * (1.0 - tc) + at(a, c + 1) * tc;
+        let high = at(a + 1, c) * (1.0 - tc) + at(a + 1, c + 1) * tc;
+        low * (1.0 - ta) + high * ta
+    }
+
+    fn average(&self, alpha: f32) -> f32 {
+        let (a, ta) = AlbedoTable::locate(alpha);
+        self.average[a] * (1.0 - ta) + self.average[a + 1] * ta
+    }
+}
+
+/// Microfacet normal that refracts `wo` into `wi`, facing up, where `eta` is the index on the
+/// side of `wi` over the index on the side of `wo`.
+#[inline]
+pub fn transmission_half(wo: Vec3, wi: Vec3, eta: f32) -> Vec3 {
+    let h = (wo + wi * eta).normalized();
+    if h.z < 0.0 {
+        -h
+    } else {
+        h
+    }
+}
+
+/// Share of light a smooth dielectric reflects, with `cos_i` measured on the incoming side and
+/// `eta` the index across the surface over the index on that side.
+#[inline]
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (parallel * parallel + perpendicular * perpendicular)
+}
+
+/// Reflectance of a conductor with complex index `eta + i k`, per channel.
+#[inline]
+pub fn fresnel_conductor(cos_i: f32, eta: Vec3, k: Vec3) -> Vec3 {
+    let channel = |eta: f32, k: f32| {
+        let cos2 = cos_i.clamp(0.0, 1.0).powi(2);
+        let sin2 = 1.0 - cos2;
+        let t0 = eta * eta - k * k - sin2;
+        let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+        let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+        let t1 = a2_plus_b2 + cos2;
+        let t2 = 2.0 * cos_i.clamp(0.0, 1.0) * a;
+        let perpendicular = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let parallel = perpendicular * (t3 - t4) / (t3 + t4);
+        0.5 * (perpendicular + parallel)
+    };
+    Vec3::new(
+        channel(eta.x, k.x),
+        channel(eta.y, k.y),
+        channel(eta.z, k.z),
+    )
+}
+
+// Measured complex indices (eta, k) of common metals, reduced from spectral data to RGB
+const MEASURED_CONDUCTORS: [(&str, [f32; 3], [f32; 3]); 5] = [
+    (
+        "gold",
+        [0.143119, 0.374957, 1.44248],
+        [3.98316, 2.38572, 1.60322],
+    ),
+    (
+        "copper",
+        [0.200438, 0.924033, 1.10221],
+        [3.91295, 2.45285, 2.14219],
+    ),
+    (
+        "aluminium",
+        [1.65746, 0.880369, 0.521229],
+        [9.22387, 6.26952, 4.837],
+    ),
+    (
+        "silver",
+        [0.155265, 0.116723, 0.138342],
+        [4.82835, 3.12225, 2.14696],
+    ),
+    (
+        "chrome",
+        [4.36968, 2.9167, 1.6547],
+        [5.20637, 4.23132, 3.75497],
+    ),
+];
+
+/// Complex index of a metal from the measured table by name.
+pub fn measured_conductor(name: &str) -> Option<(Vec3, Vec3)> {
+    let name = match name {
+        "aluminum" => "aluminium",
+        "chromium" => "chrome",
+        name => name,
+    };
+    MEASURED_CONDUCTORS
+        .iter()
+        .find(|(preset, _, _)| *preset == name)
+        .map(|(_, eta, k)| (Vec3::from(*eta), Vec3::from(*k)))
+}
+
+/// Names accepted by `measured_conductor`.
+pub fn conductor_names() -> impl Iterator<Item = &'static str> {
+    MEASURED_CONDUCTORS.iter().map(|(name, _, _)| *name)
+}
+
+/// Complex index of a conductor that reflects `reflectance` head on and turns white at grazing
+/// angles, from Gulbrandsen's artist friendly mapping.
+pub fn conductor_index(reflectance: Vec3) -> (Vec3, Vec3) {
+    let r = reflectance.clamped(Vec3::zero(), Vec3::one() * 0.999);
+    let eta = (Vec3::one() - r) / (Vec3::one() + r);
+    let k2 = (r * (eta + Vec3::one()) * (eta + Vec3::one())
+        - (eta - Vec3::one()) * (eta - Vec3::one()))
+        / (Vec3::one() - r);
+    (eta, k2.max_by_component(Vec3::zero()).map(f32::sqrt))
+}
+
+/// Cosine weighted average of a Fresnel term over the hemisphere.
+pub fn fresnel_average(fresnel: impl Fn(f32) -> Vec3) -> Vec3 {
+    // Midpoint rule for 2 * integral of F(cos) * cos
+    let steps = ALBEDO_STEPS;
+    (0..steps)
+        .map(|i| {
+            let cosine = (i as f32 + 0.5) / steps as f32;
+            fresnel(cosine) * (2.0 * cosine / steps as f32)
+        })
+        .fold(Vec3::zero(), |total, x| total + x)
+}
+
+/// `wo` refracted through a microfacet with normal `h`, where `eta` is the index across over
+/// the index on the side of `wo`. `None` on total internal reflection.
+#[inline]
+pub fn refract(wo: Vec3, h: Vec3, eta: f32) -> Option<Vec3> {
+    let cos_i = wo.dot(h);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-wo / eta + (cos_i / eta - cos_t) * h)
+}
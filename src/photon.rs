@@ -0,0 +1,274 @@
+use std::f32::consts::PI;
+
+use ultraviolet::Vec3;
+
+use crate::{
+    random::Sampler,
+    ray::Ray,
+    render::{tint, T_MAX, T_MIN},
+    spectrum::Wavelengths,
+    tracer::{
+        bvh::Bvh,
+        hittable::{HitRecord, Hittable},
+        lights::Lights,
+    },
+};
+
+// How much of the gather area each pass keeps, from progressive photon mapping
+const ALPHA: f32 = 2.0 / 3.0;
+
+/// Light carried to a diffuse surface through mirrors or glass.
+#[derive(Debug, Copy, Clone)]
+pub struct Photon {
+    pub position: Vec3,
+    /// Direction the photon was travelling in when it landed.
+    pub dir: Vec3,
+    pub power: Vec3,
+}
+
+/// Caustic photons of one pass, kept as a balanced kd-tree in a single array. Each node is
+/// the median of its slice along the axis stored beside it, with its subtrees either side.
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<u8>,
+    radius: f32,
+    // Spreads each photon's power over the gather disk and the photons shot
+    scale: f32,
+}
+
+impl PhotonMap {
+    /// Tree over the photons that landed out of `emitted` shot, gathered within `radius`.
+    pub fn new(mut photons: Vec<Photon>, emitted: usize, radius: f32) -> PhotonMap {
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        PhotonMap {
+            photons,
+            axes,
+            radius,
+            scale: (emitted.max(1) as f32 * PI * radius * radius).recip(),
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    /// Caustic light leaving `hit` back along `ray`, from the density of nearby photons.
+    pub fn estimate(&self, ray: Ray, hit: HitRecord) -> Vec3 {
+        let mut total = Vec3::zero();
+        gather(
+            &self.photons,
+            &self.axes,
+            hit.point,
+            self.radius * self.radius,
+            &mut |photon| {
+                let cosine = hit.normal.dot(photon.dir).abs();
+                if cosine > 0.0 {
+                    let (bsdf, _) = hit.material.eval(ray, hit, -photon.dir);
+                    total += bsdf / cosine * photon.power;
+                }
+            },
+        );
+        total * self.scale
+    }
+}
+
+/// Gather radius of pass `pass`. The area shrinks by a little less than the photon count
+/// grows, so the blur fades while every pass still finds photons.
+pub fn radius(initial: f32, pass: u32) -> f32 {
+    (1..=pass)
+        .fold(initial * initial, |area, i| {
+            area * (i as f32 + ALPHA) / (i as f32 + 1.0)
+        })
+        .sqrt()
+}
+
+/// Follows one photon from a light and returns it if it reaches a surface that isn't a perfect
+/// mirror or window after at least one specular bounce, with the scene as it is at `time`.
+/// Photons landing anywhere else are left to the path tracer.
+pub fn shoot(
+    world: &Bvh,
+    lights: &Lights,
+    max_bounce: u32,
+    wavelengths: Option<Wavelengths>,
+    time: f32,
+    sampler: &mut Sampler,
+) -> Option<Photon> {
+    let (r1, r2) = sampler.get_2d();
+    let (r3, r4) = sampler.get_2d();
+    let (hit, pdf_pos) = lights.sample_surface(r1, r2)?;
+    let emitter = hit.material.emitter()?;
+    let (dir, pdf_dir) = emitter.sample(hit.normal, r3, r4);
+    if pdf_dir <= 0.0 {
+        return None;
+    }
+    let radiance = emitter.emitted(Ray::new(hit.point + dir, -dir), hit);
+    let start = tint(radiance, wavelengths) * hit.normal.dot(dir).abs() / (pdf_pos * pdf_dir);
+    if start.component_max() <= 0.0 {
+        return None;
+    }
+    let mut power = start;
+    let mut ray = Ray::new(hit.point, dir)
+        .with_wavelengths(wavelengths)
+        .with_time(time);
+    let mut dispersed = false;
+    for bounce in 0..max_bounce {
+        let hit = world.hit(&ray, T_MIN, T_MAX, sampler)?;
+        if !hit.material.scatters() {
+            return None;
+        }
+        // Stored on anything light sampling can reach, whichever lobe the photon would take
+        if !hit.material.is_delta() {
+            if bounce == 0 || hit.material.is_medium() {
+                return None;
+            }
+            return Some(Photon {
+                position: hit.point,
+                dir: ray.dir,
+                power: wavelengths.map_or(power, |w| w.to_rgb(power)),
+            });
+        }
+        let scatter = hit.material.scatter(ray, hit, sampler);
+        power *= tint(scatter.attenuation, wavelengths);
+        if scatter.dispersed && !dispersed {
+            // Only the hero wavelength follows the refracted ray
+            dispersed = true;
+            power *= Vec3::new(3.0, 0.0, 0.0);
+        }
+        let survival = (power.component_max() / start.component_max()).min(1.0);
+        if survival < sampler.get_1d() {
+            return None;
+        }
+        power /= survival;
+        ray = scatter.ray.with_wavelengths(wavelengths);
+    }
+    None
+}
+
+/// Sorts `photons` into a kd-tree, splitting each slice at its median along its widest axis.
+fn build(photons: &mut [Photon], axes: &mut [u8]) {
+    if photons.len() <= 1 {
+        return;
+    }
+    let (min, max) = photons.iter().fold(
+        (Vec3::one() * f32::MAX, Vec3::one() * f32::MIN),
+        |(min, max), photon| {
+            (
+                min.min_by_component(photon.position),
+                max.max_by_component(photon.position),
+            )
+        },
+    );
+    let extent = max - min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z {
+        0
+    } else if extent.y >= extent.z {
+        1
+    } else {
+        2
+    };
+    let mid = photons.len() / 2;
+    photons.select_nth_unstable_by(mid, |a, b| a.position[axis].total_cmp(&b.position[axis]));
+    axes[mid] = axis as u8;
+    let (left, rest) = photons.split_at_mut(mid);
+    let (left_axes, rest_axes) = axes.split_at_mut(mid);
+    build(left, left_axes);
+    build(&mut rest[1..], &mut rest_axes[1..]);
+}
+
+/// Calls `found` with every photon of the tree within `sqrt(radius_sq)` of `point`.
+fn gather<F>(photons: &[Photon], axes: &[u8], point: Vec3, radius_sq: f32, found: &mut F)
+where
+    F: FnMut(&Photon),
+{
+    if photons.is_empty() {
+        return;
+    }
+    let mid = photons.len() / 2;
+    let photon = &photons[mid];
+    if (photon.position - point).mag_sq() <= radius_sq {
+        found(photon);
+    }
+    let axis = axes[mid] as usize;
+    let offset = point[axis] - photon.position[axis];
+    let (near, far) = if offset < 0.0 {
+        (
+            (&photons[..mid], &axes[..mid]),
+            (&photons[mid + 1..], &axes[mid + 1..]),
+        )
+    } else {
+        (
+            (&photons[mid + 1..], &axes[mid + 1..]),
+            (&photons[..mid], &axes[..mid]),
+        )
+    };
+    gather(near.0, near.1, point, radius_sq, found);
+    if offset * offset <= radius_sq {
+        gather(far.0, far.1, point, radius_sq, found);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Photons told apart by the red channel of their power, some stacked on the same spot.
+    fn photons(count: usize, sampler: &mut Sampler) -> Vec<Photon> {
+        (0..count)
+            .map(|i| {
+                let position = if i % 10 == 0 {
+                    Vec3::new(0.5, 0.5, 0.5)
+                } else {
+                    Vec3::new(sampler.get_1d(), sampler.get_1d(), sampler.get_1d() * 0.1)
+                };
+                Photon {
+                    position,
+                    dir: -Vec3::unit_y(),
+                    power: Vec3::new(i as f32, 0.0, 0.0),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn gather_finds_what_brute_force_does() {
+        let mut sampler = Sampler::from_seed(6);
+        for count in [0, 1, 2, 7, 500] {
+            let all = photons(count, &mut sampler);
+            let map = PhotonMap::new(all.clone(), count, 0.1);
+            for _ in 0..200 {
+                let point = Vec3::new(sampler.get_1d(), sampler.get_1d(), sampler.get_1d() * 0.1);
+                let radius_sq = (sampler.get_1d() * 0.3).powi(2);
+                let mut found = Vec::new();
+                gather(&map.photons, &map.axes, point, radius_sq, &mut |photon| {
+                    found.push(photon.power.x as usize)
+                });
+                found.sort_unstable();
+                let expected = all
+                    .iter()
+                    .filter(|photon| (photon.position - point).mag_sq() <= radius_sq)
+                    .map(|photon| photon.power.x as usize)
+                    .collect::<Vec<_>>();
+                assert_eq!(found, expected, "{count} photons around {point:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn radius_shrinks_by_alpha_each_pass() {
+        assert_eq!(radius(0.5, 0), 0.5);
+        let mut area = 0.25;
+        for pass in 1..50 {
+            area *= (pass as f32 + ALPHA) / (pass as f32 + 1.0);
+            let shrunk = radius(0.5, pass);
+            assert!((shrunk * shrunk - area).abs() < 1e-6 * area.max(1e-3));
+            assert!(shrunk < radius(0.5, pass - 1));
+        }
+    }
+}
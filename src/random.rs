@@ -4,10 +4,187 @@ use ultraviolet::Vec3;
 
 use crate::ray::Onb;
 
+/// Random stream behind every decision along a path, reproducible from the render seed.
+/// A replayable stream hands out a stored primary sample vector instead, which Metropolis
+/// chains mutate between paths.
+#[derive(Clone, Debug)]
+pub struct Sampler {
+    rng: fastrand::Rng,
+    primary: Option<Box<PrimarySamples>>,
+}
+
+impl Sampler {
+    /// Stream for one pixel in one pass, the same no matter which thread renders it.
+    pub fn new(seed: u64, pass: u32, pixel: usize) -> Sampler {
+        Sampler::from_seed(mix(seed ^ mix(pass as u64 ^ mix(pixel as u64))))
+    }
+
+    pub fn from_seed(seed: u64) -> Sampler {
+        Sampler {
+            rng: fastrand::Rng::with_seed(seed),
+            primary: None,
+        }
+    }
+
+    /// Stream that remembers every value it hands out. Built twice from the same arguments,
+    /// it gives the same path until the first call to `mutate`.
+    pub fn replayable(
+        seed: u64,
+        pass: u32,
+        index: usize,
+        sigma: f32,
+        large_step_probability: f32,
+    ) -> Sampler {
+        Sampler {
+            primary: Some(Box::new(PrimarySamples {
+                values: Vec::new(),
+                index: 0,
+                iteration: 0,
+                large_step: true,
+                last_large_step: 0,
+                sigma,
+                large_step_probability,
+            })),
+            ..Sampler::new(seed, pass, index)
+        }
+    }
+
+    /// Starts the next path, perturbing every value it reads from the last accepted one,
+    /// or now and then drawing them all afresh. Does nothing for a plain stream.
+    pub fn mutate(&mut self) {
+        if let Some(primary) = &mut self.primary {
+            primary.iteration += 1;
+            primary.large_step = self.rng.f32() < primary.large_step_probability;
+            primary.index = 0;
+        }
+    }
+
+    /// Keeps the values of the path since the last `mutate`.
+    pub fn accept(&mut self) {
+        if let Some(primary) = &mut self.primary {
+            if primary.large_step {
+                primary.last_large_step = primary.iteration;
+            }
+        }
+    }
+
+    /// Goes back to the values of the last accepted path.
+    pub fn reject(&mut self) {
+        if let Some(primary) = &mut self.primary {
+            let iteration = primary.iteration;
+            primary
+                .values
+                .iter_mut()
+                .filter(|value| value.modified == iteration)
+                .for_each(PrimarySample::restore);
+            primary.iteration -= 1;
+        }
+    }
+
+    #[inline]
+    fn uniform(&mut self) -> f32 {
+        match &mut self.primary {
+            Some(primary) => primary.get(&mut self.rng),
+            None => self.rng.f32(),
+        }
+    }
+
+    #[inline]
+    pub fn get_1d(&mut self) -> f32 {
+        self.uniform()
+    }
+
+    #[inline]
+    pub fn get_2d(&mut self) -> (f32, f32) {
+        (self.uniform(), self.uniform())
+    }
+
+    #[inline]
+    pub fn get_f64(&mut self) -> f64 {
+        match self.primary {
+            Some(_) => self.uniform() as f64,
+            None => self.rng.f64(),
+        }
+    }
+
+    #[inline]
+    pub fn index(&mut self, len: usize) -> usize {
+        match self.primary {
+            Some(_) => ((self.uniform() * len as f32) as usize).min(len - 1),
+            None => self.rng.usize(0..len),
+        }
+    }
+}
+
+/// One value of a primary sample vector, with the iteration that last changed it and what it
+/// was before, for undoing a rejected mutation.
+#[derive(Copy, Clone, Debug, Default)]
+struct PrimarySample {
+    value: f32,
+    modified: u64,
+    backup: (f32, u64),
+}
+
+impl PrimarySample {
+    #[inline]
+    fn restore(&mut self) {
+        (self.value, self.modified) = self.backup;
+    }
+}
+
+/// Primary sample vector of a Metropolis chain, after Kelemen et al. Values are only brought
+/// up to date when a path reads them, so paths of any length cost the same to mutate.
+#[derive(Clone, Debug)]
+struct PrimarySamples {
+    values: Vec<PrimarySample>,
+    index: usize,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    sigma: f32,
+    large_step_probability: f32,
+}
+
+impl PrimarySamples {
+    fn get(&mut self, rng: &mut fastrand::Rng) -> f32 {
+        if self.index >= self.values.len() {
+            self.values.resize(self.index + 1, PrimarySample::default());
+        }
+        let sample = &mut self.values[self.index];
+        self.index += 1;
+        // Untouched since a large step was accepted, so it was drawn afresh there too
+        if sample.modified < self.last_large_step {
+            sample.value = rng.f32();
+            sample.modified = self.last_large_step;
+        }
+        sample.backup = (sample.value, sample.modified);
+        if self.large_step {
+            sample.value = rng.f32();
+        } else {
+            // Every small step it missed, applied at once as a single wider one
+            let steps = (self.iteration - sample.modified) as f32;
+            let normal = (-2.0 * (1.0 - rng.f32()).ln()).sqrt() * (2.0 * PI * rng.f32()).cos();
+            sample.value += normal * self.sigma * steps.sqrt();
+            sample.value = (sample.value - sample.value.floor()).min(1.0 - f32::EPSILON);
+        }
+        sample.modified = self.iteration;
+        sample.value
+    }
+}
+
+/// SplitMix64 finalizer, spreads nearby seeds into unrelated streams.
+#[inline]
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
 #[inline]
-pub fn quasirandom_in_unit_sphere(r1: f32, r2: f32) -> Vec3 {
+pub fn quasirandom_in_unit_sphere(r1: f32, r2: f32, r3: f32) -> Vec3 {
     let rand_unit_vec = quasirandom_on_uniform_sphere(r1, r2);
-    rand_unit_vec * fastrand::f32()
+    rand_unit_vec * r3
 }
 
 #[inline]
@@ -42,12 +219,116 @@ pub fn quasirandom_on_uniform_sphere(r1: f32, r2: f32) -> Vec3 {
     Vec3::new(x, y, z)
 }
 
+#[inline]
+pub fn quasirandom_in_cone(cos_theta_max: f32, r1: f32, r2: f32) -> Vec3 {
+    let z = 1.0 + r2 * (cos_theta_max - 1.0);
+    let phi = 2.0 * PI * r1;
+    let sin_theta = (1.0 - z * z).max(0.0).sqrt();
+
+    Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, z)
+}
+
 #[inline]
 pub fn quasirandom_on_hemisphere(normal: Vec3, r1: f32, r2: f32) -> Vec3 {
     Onb::from_w(&normal).local(quasirandom_on_cosine_sphere(r1, r2))
 }
 
-#[inline]
-pub fn random_in_unit_sphere() -> Vec3 {
-    quasirandom_in_unit_sphere(fastrand::f32(), fastrand::f32())
+/// Piecewise-constant distribution over `[0, 1)` with one bucket per weight.
+#[derive(Debug, Clone)]
+pub struct Distribution {
+    weights: Vec<f32>,
+    cdf: Vec<f32>,
+    total: f32,
+}
+
+impl Distribution {
+    pub fn new(weights: Vec<f32>) -> Distribution {
+        let mut cdf = Vec::with_capacity(weights.len() + 1);
+        cdf.push(0.0);
+        let mut total = 0.0;
+        weights.iter().for_each(|weight| {
+            total += weight.max(0.0);
+            cdf.push(total);
+        });
+        Distribution {
+            weights,
+            cdf,
+            total,
+        }
+    }
+
+    #[inline]
+    pub fn total(&self) -> f32 {
+        self.total
+    }
+
+    /// Picks a bucket, returning its index and where `r` landed inside it, rescaled to `[0, 1)`.
+    #[inline]
+    pub fn sample(&self, r: f32) -> (usize, f32) {
+        let target = r * self.total;
+        let index = self
+            .cdf
+            .partition_point(|&c| c <= target)
+            .clamp(1, self.weights.len())
+            - 1;
+        let width = self.cdf[index + 1] - self.cdf[index];
+        let offset = if width > 0.0 {
+            ((target - self.cdf[index]) / width).clamp(0.0, 1.0 - f32::EPSILON)
+        } else {
+            0.5
+        };
+        (index, offset)
+    }
+
+    /// Chance of `sample` picking bucket `index`.
+    #[inline]
+    pub fn probability(&self, index: usize) -> f32 {
+        if self.total > 0.0 {
+            self.weights[index].max(0.0) / self.total
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distribution_sample_inverts_the_cdf() {
+        let distribution = Distribution::new(vec![1.0, 0.0, 3.0, 0.5, -2.0, 1.5]);
+        for step in 0..1000 {
+            let r = step as f32 / 1000.0;
+            let (index, offset) = distribution.sample(r);
+            assert!(
+                distribution.probability(index) > 0.0,
+                "picked empty bucket {index}"
+            );
+            let below = (0..index).map(|i| distribution.probability(i)).sum::<f32>();
+            let back = below + offset * distribution.probability(index);
+            assert!((back - r).abs() < 1e-5, "{r} came back as {back}");
+        }
+    }
+
+    #[test]
+    fn distribution_picks_buckets_by_probability() {
+        let distribution = Distribution::new(vec![1.0, 0.0, 3.0, 0.5, -2.0, 1.5]);
+        let mut counts = [0usize; 6];
+        let mut sampler = Sampler::from_seed(3);
+        let draws = 100_000;
+        for _ in 0..draws {
+            counts[distribution.sample(sampler.get_1d()).0] += 1;
+        }
+        let total = (0..6).map(|i| distribution.probability(i)).sum::<f32>();
+        assert!((total - 1.0).abs() < 1e-6);
+        for (index, count) in counts.into_iter().enumerate() {
+            let expected = distribution.probability(index);
+            let frequency = count as f32 / draws as f32;
+            assert!(
+                (frequency - expected).abs() < 0.01,
+                "bucket {index}: {frequency}"
+            );
+        }
+    }
 }
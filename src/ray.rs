@@ -1,9 +1,27 @@
 use ultraviolet::Vec3;
 
+use crate::spectrum::Wavelengths;
+
 #[derive(Copy, Clone, Debug)]
 pub struct Ray {
     pub pos: Vec3,
     pub dir: Vec3,
+    /// Wavelengths carried by the path when tracing spectrally.
+    pub wavelengths: Option<Wavelengths>,
+    /// Moment within the shutter interval, zero to one, that the ray was sent at.
+    pub time: f32,
+    /// Rays through the neighbouring pixels, while the path has only bounced specularly.
+    pub differentials: Option<Differentials>,
+}
+
+/// Origins and directions of the rays one pixel over in x and in y, followed alongside a ray
+/// so its hits know how much of the surface a pixel covers.
+#[derive(Copy, Clone, Debug)]
+pub struct Differentials {
+    pub x_pos: Vec3,
+    pub x_dir: Vec3,
+    pub y_pos: Vec3,
+    pub y_dir: Vec3,
 }
 
 impl Ray {
@@ -11,6 +29,30 @@ impl Ray {
         Ray {
             pos,
             dir: dir.normalized(),
+            wavelengths: None,
+            time: 0.0,
+            differentials: None,
+        }
+    }
+
+    #[inline]
+    pub fn with_wavelengths(self, wavelengths: Option<Wavelengths>) -> Ray {
+        Ray {
+            wavelengths,
+            ..self
+        }
+    }
+
+    #[inline]
+    pub fn with_time(self, time: f32) -> Ray {
+        Ray { time, ..self }
+    }
+
+    #[inline]
+    pub fn with_differentials(self, differentials: Option<Differentials>) -> Ray {
+        Ray {
+            differentials,
+            ..self
         }
     }
 
@@ -48,4 +90,9 @@ impl Onb {
     pub fn local(&self, a: Vec3) -> Vec3 {
         a.x * self.u + a.y * self.v + a.z * self.w
     }
+
+    #[inline]
+    pub fn to_local(&self, a: Vec3) -> Vec3 {
+        Vec3::new(a.dot(self.u), a.dot(self.v), a.dot(self.w))
+    }
 }
@@ -1,20 +1,39 @@
-use crate::tracer::hittable::Hittable;
-use std::f32::consts::PI;
-use std::f32::INFINITY;
+use crate::tracer::{
+    hittable::{HitRecord, Hittable},
+    lights::Lights,
+};
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
+use crate::bidirectional::Bidirectional;
+use crate::environment::Environment;
+use crate::film::{heatmap, Film, FirstHit, Pixel};
 use crate::material::Scatter;
+use crate::metropolis::Metropolis;
+use crate::photon::{self, PhotonMap};
+use crate::random::{quasirandom_on_hemisphere, Sampler};
 use crate::ray::Ray;
-use crate::{camera::Camera, tracer::bvh::Bvh};
+use crate::spectrum::Wavelengths;
+use crate::tile::Tile;
+use crate::{
+    camera::Camera,
+    tracer::bvh::{Bvh, TraversalStats},
+};
+use clap::ValueEnum;
 use quasirandom::Qrng;
-use radiant::Image;
 use rayon::prelude::*;
 use ultraviolet::{Mat3, Vec3};
 
-const T_MIN: f32 = 0.00015;
-const T_MAX: f32 = 100000.0;
+pub const T_MIN: f32 = 0.00015;
+pub const T_MAX: f32 = 100000.0;
 const CHUNK_NUM: usize = 64;
+// Tests per camera ray at the top of the traversal heatmap, on a log scale
+const MAX_COST: f32 = 1000.0;
+// Keeps the quasirandom offsets of a tile apart from its first pixel's stream
+const QRNG_STREAM: u64 = 0x5851_f42d_4c95_7f2d;
+// Keeps photon streams apart from the pixel streams of the same pass
+const PHOTON_STREAM: u64 = 0x1405_7b7e_f767_814f;
 
 // Tonemapping constants
 const M1: Mat3 = Mat3::new(
@@ -28,16 +47,36 @@ const M2: Mat3 = Mat3::new(
     Vec3::new(-0.07367, -0.00605, 1.07602),
 );
 
-#[derive(Copy, Clone, Hash)]
+#[derive(Copy, Clone, Hash, Debug, PartialEq, Eq, ValueEnum)]
 pub enum Mode {
     Normals,
     Colors,
     Image,
+    /// Clay render, shaded only by how open the surroundings of each hit are
+    AmbientOcclusion,
+    /// Bounding box and primitive tests of each camera ray as a false colour heatmap
+    TraversalCost,
+}
+
+/// Light transport algorithm behind the image mode.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Integrator {
+    /// Camera paths with light and environment sampling at every bounce
+    Path,
+    /// Camera and light subpaths joined at every pair of vertices, for light behind glass
+    Bidirectional,
+    /// Markov chains that mutate the random numbers of camera paths, for light through gaps
+    Metropolis,
 }
 
 #[inline]
 pub fn to_rgb(color: &Vec3, gamma: f32) -> u32 {
-    let out = aces_tonemap(color, gamma);
+    pack(&aces_tonemap(color, gamma))
+}
+
+/// Packs a colour already in display range, such as a debug view, without tonemapping.
+#[inline]
+pub fn pack(out: &Vec3) -> u32 {
     255 << 24
         | ((out.x * 255.4) as u32) << 16
         | ((out.y * 255.4) as u32) << 8
@@ -55,92 +94,307 @@ fn aces_tonemap(color: &Vec3, gamma: f32) -> Vec3 {
 }
 
 #[inline]
-fn ray_color(ray: Ray, world: &Bvh, depth: u32, image: &Option<Image>, light_clamp: f32) -> Vec3 {
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b > 0.0 {
+        a / (a + b)
+    } else {
+        0.0
+    }
+}
+
+/// RGB `color` as seen by the path, upsampled when tracing spectrally.
+#[inline]
+pub fn tint(color: Vec3, wavelengths: Option<Wavelengths>) -> Vec3 {
+    wavelengths.map_or(color, |w| w.from_rgb(color))
+}
+
+/// Limits that trade a little bias for fewer fireflies.
+#[derive(Copy, Clone, Debug)]
+pub struct Fireflies {
+    /// Cap on light that scattered once before reaching the camera
+    pub direct_clamp: f32,
+    /// Cap on light that scattered more than once
+    pub indirect_clamp: f32,
+    /// Smallest metal and glossy roughness once a path has bounced off something rough,
+    /// path integrator only
+    pub regularize: f32,
+}
+
+impl Fireflies {
+    /// Light that bounced `bounces` times on its way to the camera, scaled down to its cap
+    /// with its hue kept. Lights and sky seen straight from the camera are never clamped.
+    #[inline]
+    pub fn clamp(self, contribution: Vec3, bounces: usize) -> Vec3 {
+        let cap = match bounces {
+            0 => return contribution,
+            1 => self.direct_clamp,
+            _ => self.indirect_clamp,
+        };
+        let peak = contribution.component_max();
+        if peak > cap {
+            contribution * (cap / peak)
+        } else {
+            contribution
+        }
+    }
+}
+
+/// Radiance along `ray`, as RGB or, when it carries wavelengths, one value per wavelength.
+/// With `caustics`, light reaching the first diffuse surface through mirrors and glass is
+/// read from the photons instead of found by the path.
+#[inline]
+#[allow(clippy::too_many_arguments)]
+pub fn ray_color(
+    ray: Ray,
+    world: &Bvh,
+    lights: &Lights,
+    depth: u32,
+    image: &Option<Environment>,
+    fireflies: Fireflies,
+    caustics: Option<&PhotonMap>,
+    sampler: &mut Sampler,
+) -> Vec3 {
+    let wavelengths = ray.wavelengths;
     let mut color_total = Vec3::one();
+    let mut radiance = Vec3::zero();
     let mut temp_ray = ray;
-    for _ in 0..depth {
-        if let Some(hit) = world.hit(&temp_ray, T_MIN, T_MAX) {
-            let scatter: Scatter =
-                hit.material
-                    .scatter(temp_ray, hit, fastrand::f32(), fastrand::f32());
-            if scatter.attenuation.component_max() <= 1.0 {
-                color_total *= scatter.attenuation;
-                if color_total.component_max() < fastrand::f32() {
-                    break;
+    let mut dispersed = false;
+    // Density of the last bounce direction, when light was also sampled directly from there
+    let mut bsdf_pdf: Option<f32> = None;
+    let mut diffuse_seen = false;
+    // Only specular bounces since photons were gathered, so any light found is already counted
+    let mut from_gather = false;
+    let mut caustic = false;
+    for bounce in 0..depth as usize {
+        if let Some(hit) = world.hit(&temp_ray, T_MIN, T_MAX, sampler) {
+            if hit.material.is_emissive() && !caustic {
+                let weight = bsdf_pdf.map_or(1.0, |pdf| {
+                    power_heuristic(pdf, lights.pdf_value(temp_ray.pos, temp_ray.dir))
+                });
+                radiance += fireflies.clamp(
+                    color_total * weight * tint(hit.material.emitted(temp_ray, hit), wavelengths),
+                    bounce,
+                );
+            }
+            if !hit.material.scatters() {
+                return radiance;
+            }
+            // Blurs sharp lobes behind a rough bounce, but not along a caustic chain, which
+            // the photons already cover
+            let regularized = (diffuse_seen && !from_gather)
+                .then(|| hit.material.regularized(fireflies.regularize))
+                .flatten();
+            let hit = match &regularized {
+                Some(material) => HitRecord { material, ..hit },
+                None => hit,
+            };
+            let scatter: Scatter = hit.material.scatter(temp_ray, hit, sampler);
+            bsdf_pdf = None;
+            // Light is sampled for every lobe but the delta ones, whichever lobe was picked
+            if !hit.material.is_delta() {
+                if !lights.is_empty() {
+                    radiance += fireflies.clamp(
+                        color_total * sample_lights(temp_ray, hit, world, lights, sampler),
+                        bounce + 1,
+                    );
+                }
+                if let Some(env) = image {
+                    radiance += fireflies.clamp(
+                        color_total * sample_environment(temp_ray, hit, world, env, sampler),
+                        bounce + 1,
+                    );
+                }
+                from_gather = false;
+                let gather = !diffuse_seen && !hit.material.is_medium();
+                if let Some(photons) = caustics.filter(|_| gather) {
+                    // At least one specular bounce before this one
+                    radiance += fireflies.clamp(
+                        color_total * tint(photons.estimate(temp_ray, hit), wavelengths),
+                        bounce + 2,
+                    );
+                    // The photons only cover the lobes light sampling does
+                    from_gather = !scatter.specular;
+                }
+                if !scatter.specular {
+                    bsdf_pdf = Some(scatter.pdf);
+                    diffuse_seen = true;
                 }
-                color_total *= color_total.component_max().recip();
-                temp_ray = scatter.ray;
-            } else {
-                return color_total
-                    * scatter
-                        .attenuation
-                        .clamped(Vec3::zero(), Vec3::one() * light_clamp);
             }
+            caustic = from_gather && scatter.specular;
+            color_total *= tint(scatter.attenuation, wavelengths);
+            if scatter.dispersed && !dispersed {
+                // Only the hero wavelength follows the refracted ray
+                dispersed = true;
+                color_total *= Vec3::new(3.0, 0.0, 0.0);
+            }
+            // Paths brighter than one always survive, so nothing is divided away
+            let survival = color_total.component_max().min(1.0);
+            if survival < sampler.get_1d() {
+                return radiance;
+            }
+            color_total /= survival;
+            temp_ray = scatter.ray.with_wavelengths(wavelengths);
         } else {
-            return color_total * get_sky(temp_ray, image, light_clamp);
+            let weight = match (bsdf_pdf, image) {
+                (Some(pdf), Some(env)) => power_heuristic(pdf, env.pdf(temp_ray.dir)),
+                _ => 1.0,
+            };
+            return radiance
+                + fireflies.clamp(
+                    color_total * weight * tint(get_sky(temp_ray, image), wavelengths),
+                    bounce,
+                );
         }
     }
-    color_total * 0.01
+    radiance + color_total * tint(Vec3::one() * 0.01, wavelengths)
 }
 
+/// Direct light through a single shadow ray, weighted against the BSDF sample.
 #[inline]
-fn get_pixel_from_vec(dir: Vec3, image: &Option<Image>) -> Option<Vec3> {
-    if let Some(img) = image.as_ref() {
-        let u = (dir.x.atan2(dir.z) + PI) / (2.0 * PI);
-        let v = (-dir.y).acos() / PI;
-
-        if u <= 1.0 && v <= 1.0 {
-            let color = img.pixel(
-                (u * (img.width - 1) as f32) as usize,
-                ((1.0 - v) * (img.height - 1) as f32) as usize,
-            );
-            Some(Vec3::new(color.r, color.g, color.b))
-        } else {
-            None
+fn sample_lights(
+    ray: Ray,
+    hit: HitRecord,
+    world: &Bvh,
+    lights: &Lights,
+    sampler: &mut Sampler,
+) -> Vec3 {
+    let (r1, r2) = sampler.get_2d();
+    let dir = lights.random(hit.point, r1, r2).normalized();
+    let light_pdf = lights.pdf_value(hit.point, dir);
+    if light_pdf <= 0.0 {
+        return Vec3::zero();
+    }
+    let (bsdf, bsdf_pdf) = hit.material.eval(ray, hit, dir);
+    if bsdf == Vec3::zero() {
+        return Vec3::zero();
+    }
+    let shadow_ray = Ray::new(hit.point, dir).with_time(ray.time);
+    match world.hit(&shadow_ray, T_MIN, T_MAX, sampler) {
+        Some(light_hit) if light_hit.material.is_emissive() => {
+            tint(bsdf, ray.wavelengths)
+                * (power_heuristic(light_pdf, bsdf_pdf) / light_pdf)
+                * tint(
+                    light_hit.material.emitted(shadow_ray, light_hit),
+                    ray.wavelengths,
+                )
         }
-    } else {
-        None
+        _ => Vec3::zero(),
     }
 }
 
+/// Light from the HDR map through a single shadow ray, weighted against the BSDF sample.
 #[inline]
-fn no_bounce(ray: Ray, world: &Bvh, image: &Option<Image>) -> Vec3 {
-    if let Some(hit) = world.hit(&ray, T_MIN, T_MAX) {
+fn sample_environment(
+    ray: Ray,
+    hit: HitRecord,
+    world: &Bvh,
+    env: &Environment,
+    sampler: &mut Sampler,
+) -> Vec3 {
+    let (r1, r2) = sampler.get_2d();
+    let (dir, env_pdf) = env.sample(r1, r2);
+    if env_pdf <= 0.0 {
+        return Vec3::zero();
+    }
+    let (bsdf, bsdf_pdf) = hit.material.eval(ray, hit, dir);
+    let shadow_ray = Ray::new(hit.point, dir).with_time(ray.time);
+    if bsdf == Vec3::zero() || world.hit(&shadow_ray, T_MIN, T_MAX, sampler).is_some() {
+        return Vec3::zero();
+    }
+    tint(bsdf, ray.wavelengths)
+        * (power_heuristic(env_pdf, bsdf_pdf) / env_pdf)
+        * tint(env.radiance(dir), ray.wavelengths)
+}
+
+#[inline]
+fn no_bounce(ray: Ray, world: &Bvh, image: &Option<Environment>, sampler: &mut Sampler) -> Vec3 {
+    if let Some(hit) = world.hit(&ray, T_MIN, T_MAX, sampler) {
         (Vec3::new(1.0, 1.0, -0.5))
             .normalized()
             .dot(hit.normal)
             .clamp(0.1, 1.0)
-            * hit
-                .material
-                .scatter(ray, hit, fastrand::f32(), fastrand::f32())
-                .attenuation
+            * hit.material.scatter(ray, hit, sampler).attenuation
+            + hit.material.emitted(ray, hit)
     } else {
-        get_sky(ray, image, INFINITY)
+        get_sky(ray, image)
     }
 }
 
 #[inline]
-fn normals_only(ray: Ray, world: &Bvh, image: &Option<Image>) -> Vec3 {
-    if let Some(hit) = world.hit(&ray, T_MIN, T_MAX) {
+fn first_hit(ray: Ray, world: &Bvh, camera: &Camera, sampler: &mut Sampler) -> Option<FirstHit> {
+    world.hit(&ray, T_MIN, T_MAX, sampler).map(|hit| FirstHit {
+        albedo: hit.material.color(),
+        normal: hit.normal,
+        depth: camera.depth(hit.point),
+        object_index: hit.object_index,
+        material_index: hit.material_index,
+    })
+}
+
+#[inline]
+fn normals_only(ray: Ray, world: &Bvh, image: &Option<Environment>, sampler: &mut Sampler) -> Vec3 {
+    if let Some(hit) = world.hit(&ray, T_MIN, T_MAX, sampler) {
         (hit.normal + Vec3::one()) * 0.5
     } else {
-        get_sky(ray, image, INFINITY)
+        get_sky(ray, image)
     }
 }
 
 #[inline]
-fn colors_only(ray: Ray, world: &Bvh, image: &Option<Image>) -> Vec3 {
-    if let Some(hit) = world.hit(&ray, T_MIN, T_MAX) {
+fn colors_only(ray: Ray, world: &Bvh, image: &Option<Environment>, sampler: &mut Sampler) -> Vec3 {
+    if let Some(hit) = world.hit(&ray, T_MIN, T_MAX, sampler) {
         hit.material.color()
     } else {
-        get_sky(ray, image, INFINITY)
+        get_sky(ray, image)
     }
 }
 
+/// Share of a cosine-weighted occlusion ray that escapes within `max_distance` of the first hit.
 #[inline]
-fn get_sky(ray: Ray, image: &Option<Image>, light_clamp: f32) -> Vec3 {
-    if let Some(color) = get_pixel_from_vec(ray.dir, image) {
-        Vec3::new(color[0], color[1], color[2]).clamped(Vec3::zero(), Vec3::one() * light_clamp)
+fn ambient_occlusion(
+    ray: Ray,
+    world: &Bvh,
+    image: &Option<Environment>,
+    max_distance: f32,
+    sampler: &mut Sampler,
+) -> Vec3 {
+    if let Some(hit) = world.hit(&ray, T_MIN, T_MAX, sampler) {
+        let normal = if ray.dir.dot(hit.normal) > 0.0 {
+            -hit.normal
+        } else {
+            hit.normal
+        };
+        let (r1, r2) = sampler.get_2d();
+        let occlusion_ray =
+            Ray::new(hit.point, quasirandom_on_hemisphere(normal, r1, r2)).with_time(ray.time);
+        if world
+            .hit(&occlusion_ray, T_MIN, max_distance, sampler)
+            .is_some()
+        {
+            Vec3::zero()
+        } else {
+            Vec3::one()
+        }
+    } else {
+        get_sky(ray, image)
+    }
+}
+
+/// Heatmap of the work the BVH does to find the first hit of `ray`.
+#[inline]
+fn traversal_cost(ray: Ray, world: &Bvh, sampler: &mut Sampler) -> Vec3 {
+    let start = TraversalStats::current();
+    world.hit(&ray, T_MIN, T_MAX, sampler);
+    let work = TraversalStats::current() - start;
+    let tests = (work.box_tests + work.primitive_tests) as f32;
+    heatmap((1.0 + tests).ln() / (1.0 + MAX_COST).ln())
+}
+
+#[inline]
+pub fn get_sky(ray: Ray, image: &Option<Environment>) -> Vec3 {
+    if let Some(env) = image {
+        env.radiance(ray.dir)
     } else {
         let t = 0.5 * (ray.dir.dot(Vec3::new(-1.0, 0.75, 0.5).normalized()) + 1.0);
         ((1.0 - t) * Vec3::one() + t * Vec3::new(0.1, 0.3, 0.8)) * 2.0
@@ -153,69 +407,252 @@ pub struct Renderer {
     pub height: usize,
     pub camera: Camera,
     pub world: Arc<Bvh>,
+    pub lights: Arc<Lights>,
     pub sample_rate: u32,
     pub max_bounce: u32,
-    pub hdr: Arc<Option<Image>>,
-    pub light_clamp: f32,
+    pub hdr: Arc<Option<Environment>>,
+    pub fireflies: Fireflies,
+    pub spectral: bool,
+    pub noise_threshold: f32,
+    pub seed: u64,
+    pub aovs: bool,
+    pub ao_distance: f32,
+    pub integrator: Integrator,
+    pub photons: usize,
+    pub photon_radius: f32,
 }
 impl Renderer {
-    pub fn render(&self, buffer: &[Vec3], mode: Mode) -> Vec<Vec3> {
-        (0..self.width * self.height)
+    /// One more pass over `film`, with the samples spread by each pixel's estimated error.
+    /// Tiles are started in the order given and handed to `on_tile` as soon as they finish.
+    /// Every pixel draws from its own stream, so a given seed always gives the same image.
+    pub fn render<F>(&self, film: &Film, mode: Mode, tiles: &[Tile], on_tile: F) -> Film
+    where
+        F: Fn(&Tile, &[Pixel]) + Sync,
+    {
+        if mode == Mode::Image && self.integrator == Integrator::Metropolis {
+            return self.render_metropolis(film);
+        }
+        let shares = film.sample_shares(self.sample_rate, self.noise_threshold);
+        // Shared queue rather than a parallel iterator, which would split the order up
+        let next = AtomicUsize::new(0);
+        let pixels = Mutex::new(film.pixels.clone());
+        let traversal = Mutex::new(TraversalStats::zero());
+        let tile_splats = Mutex::new(Vec::new());
+        let caustics = if mode == Mode::Image && self.integrator == Integrator::Path {
+            self.caustics(film.passes)
+        } else {
+            None
+        };
+        (0..rayon::current_num_threads())
             .into_par_iter()
-            .chunks((self.width * self.height) / CHUNK_NUM)
-            .flat_map(|chunk| {
-                let hdr = Rc::new(self.hdr.as_ref());
-                let world_bvh = Rc::new(self.world.as_ref());
-                let qrng = &mut Qrng::<(f32, f32)>::new(fastrand::f64());
-                let sample_vec = (0..(chunk.len() * self.sample_rate as usize))
-                    .map(|_| qrng.gen())
-                    .collect::<Vec<(f32, f32)>>();
-                chunk
-                    .iter()
-                    .map(|pixel| {
-                        let x = (pixel % self.width) as f32;
-                        let y = (self.height - 1 - pixel / self.width) as f32;
-                        let mut pixel_color = Vec3::zero();
-                        let mut offset = fastrand::usize(0..sample_vec.len());
-                        (0..self.sample_rate).for_each(|_| {
-                            let (jx, jy) = sample_vec[offset % sample_vec.len()];
-                            offset += 1;
-
-                            pixel_color += match mode {
-                                Mode::Normals => normals_only(
-                                    self.camera.gen_ray(self.width, self.height, x, y, jx, jy),
-                                    &world_bvh,
-                                    &hdr,
-                                ),
-                                Mode::Colors => colors_only(
-                                    self.camera.gen_ray(self.width, self.height, x, y, jx, jy),
-                                    &world_bvh,
-                                    &hdr,
-                                ),
-                                Mode::Image => ray_color(
-                                    self.camera.gen_ray(self.width, self.height, x, y, jx, jy),
+            .for_each(|_| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(tile) = tiles.get(index) else {
+                    break;
+                };
+                let start = TraversalStats::current();
+                let (done, splats) = self.render_tile(film, &shares, tile, mode, caustics.as_ref());
+                let work = TraversalStats::current() - start;
+                {
+                    let mut traversal = traversal.lock().unwrap();
+                    *traversal = *traversal + work;
+                }
+                tile_splats.lock().unwrap().push((index, splats));
+                on_tile(tile, &done);
+                let mut pixels = pixels.lock().unwrap();
+                tile.pixels(self.width)
+                    .zip(done)
+                    .for_each(|(index, pixel)| pixels[index] = pixel);
+            });
+        let pixels = pixels.into_inner().unwrap();
+        // Added in tile order, so the sums don't depend on which thread finished first
+        let mut tile_splats = tile_splats.into_inner().unwrap();
+        tile_splats.sort_by_key(|(index, _)| *index);
+        let mut splats = film.splats.clone();
+        tile_splats
+            .into_iter()
+            .flat_map(|(_, splats)| splats)
+            .for_each(|(index, splat)| splats[index] += splat);
+        let light_paths = if mode == Mode::Image && self.integrator == Integrator::Bidirectional {
+            let before = film.pixels.iter().map(|pixel| pixel.samples as u64);
+            let after = pixels.iter().map(|pixel| pixel.samples as u64);
+            after.sum::<u64>() - before.sum::<u64>()
+        } else {
+            0
+        };
+        Film {
+            pixels,
+            passes: film.passes + 1,
+            traversal: traversal.into_inner().unwrap(),
+            splats,
+            light_paths: film.light_paths + light_paths,
+            ..*film
+        }
+    }
+
+    /// One pass of Metropolis chains, with as many mutations as the pass has samples. The
+    /// chains only leave splats, so the pixels and their sample counts stay untouched.
+    fn render_metropolis(&self, film: &Film) -> Film {
+        let hdr = self.hdr.as_ref();
+        let metropolis = Metropolis {
+            world: &self.world,
+            lights: &self.lights,
+            camera: &self.camera,
+            image: hdr,
+            width: self.width,
+            height: self.height,
+            max_bounce: self.max_bounce,
+            fireflies: self.fireflies,
+            spectral: self.spectral,
+        };
+        let mutations = (self.width * self.height) as u64 * self.sample_rate as u64;
+        let (chain_splats, mutations, traversal) =
+            metropolis.render(self.seed, film.passes, mutations);
+        let mut splats = film.splats.clone();
+        splats
+            .iter_mut()
+            .zip(chain_splats)
+            .for_each(|(total, splat)| *total += splat);
+        Film {
+            pixels: film.pixels.clone(),
+            passes: film.passes + 1,
+            traversal,
+            splats,
+            light_paths: film.light_paths + mutations,
+            ..*film
+        }
+    }
+
+    /// Caustic photons for pass `pass`, or none when they are turned off or nothing emits.
+    /// Every photon draws from its own stream, so the map doesn't depend on the thread count.
+    fn caustics(&self, pass: u32) -> Option<PhotonMap> {
+        if self.photons == 0 || self.lights.is_empty() {
+            return None;
+        }
+        let photons = (0..self.photons)
+            .into_par_iter()
+            .filter_map(|index| {
+                let sampler = &mut Sampler::new(self.seed ^ PHOTON_STREAM, pass, index);
+                let wavelengths = self.spectral.then(|| Wavelengths::sample(sampler.get_1d()));
+                let time = self.camera.sample_time(sampler);
+                photon::shoot(
+                    &self.world,
+                    &self.lights,
+                    self.max_bounce,
+                    wavelengths,
+                    time,
+                    sampler,
+                )
+            })
+            .collect();
+        Some(PhotonMap::new(
+            photons,
+            self.photons,
+            photon::radius(self.photon_radius, pass),
+        ))
+    }
+
+    /// Samples of one tile, with light the bidirectional integrator traced into any pixel.
+    fn render_tile(
+        &self,
+        film: &Film,
+        shares: &[f32],
+        tile: &Tile,
+        mode: Mode,
+        caustics: Option<&PhotonMap>,
+    ) -> (Vec<Pixel>, Vec<(usize, Vec3)>) {
+        let hdr = Rc::new(self.hdr.as_ref());
+        let world_bvh = Rc::new(self.world.as_ref());
+        let first = tile.y0 * self.width + tile.x0;
+        let qrng = &mut Qrng::<(f32, f32)>::new(
+            Sampler::new(self.seed ^ QRNG_STREAM, film.passes, first).get_f64(),
+        );
+        let sample_vec = (0..(tile.len() * self.sample_rate as usize))
+            .map(|_| qrng.gen())
+            .collect::<Vec<(f32, f32)>>();
+        let bidirectional = Bidirectional {
+            world: &world_bvh,
+            lights: &self.lights,
+            camera: &self.camera,
+            image: &hdr,
+            width: self.width,
+            height: self.height,
+            max_bounce: self.max_bounce,
+            fireflies: self.fireflies,
+        };
+        let mut splats = Vec::new();
+        let pixels = tile
+            .pixels(self.width)
+            .map(|pixel| {
+                let x = (pixel % self.width) as f32;
+                let y = (self.height - 1 - pixel / self.width) as f32;
+                let sampler = &mut Sampler::new(self.seed, film.passes, pixel);
+                let mut pixel_film = film.pixels[pixel];
+                let mut offset = sampler.index(sample_vec.len());
+                // Round stochastically so small shares still get sampled on average
+                let share = shares[pixel];
+                let count = share as u32 + u32::from(sampler.get_1d() < share.fract());
+                (0..count).for_each(|_| {
+                    let (jx, jy) = sample_vec[offset % sample_vec.len()];
+                    offset += 1;
+                    let time = self.camera.sample_time(sampler);
+                    let ray = self
+                        .camera
+                        .at(time)
+                        .gen_ray(self.width, self.height, x, y, jx, jy, sampler)
+                        .with_time(time);
+                    if self.aovs {
+                        pixel_film.add_first_hit(first_hit(ray, &world_bvh, &self.camera, sampler));
+                    }
+
+                    let mut sample = match mode {
+                        Mode::Normals => normals_only(ray, &world_bvh, &hdr, sampler),
+                        Mode::Colors => colors_only(ray, &world_bvh, &hdr, sampler),
+                        Mode::AmbientOcclusion => {
+                            ambient_occlusion(ray, &world_bvh, &hdr, self.ao_distance, sampler)
+                        }
+                        Mode::TraversalCost => traversal_cost(ray, &world_bvh, sampler),
+                        Mode::Image => {
+                            let ray = if self.spectral {
+                                ray.with_wavelengths(Some(Wavelengths::sample(sampler.get_1d())))
+                            } else {
+                                ray
+                            };
+                            let color = match self.integrator {
+                                // Metropolis passes leave the tiles alone
+                                Integrator::Path | Integrator::Metropolis => ray_color(
+                                    ray,
                                     &world_bvh,
+                                    &self.lights,
                                     self.max_bounce,
                                     &hdr,
-                                    self.light_clamp,
+                                    self.fireflies,
+                                    caustics,
+                                    sampler,
                                 ),
+                                Integrator::Bidirectional => {
+                                    bidirectional.sample(ray, sampler, &mut splats)
+                                }
                             };
+                            ray.wavelengths.map_or(color, |w| w.to_rgb(color))
+                        }
+                    };
 
-                            if !pixel_color.x.is_finite() {
-                                pixel_color.x = 0.0;
-                            }
-                            if !pixel_color.y.is_finite() {
-                                pixel_color.y = 0.0;
-                            }
-                            if !pixel_color.z.is_finite() {
-                                pixel_color.z = 0.0;
-                            }
-                        });
-                        buffer[*pixel] + (pixel_color / self.sample_rate as f32)
-                    })
-                    .collect::<Vec<Vec3>>()
+                    if !sample.x.is_finite() {
+                        sample.x = 0.0;
+                    }
+                    if !sample.y.is_finite() {
+                        sample.y = 0.0;
+                    }
+                    if !sample.z.is_finite() {
+                        sample.z = 0.0;
+                    }
+                    pixel_film.add(sample);
+                });
+                pixel_film
             })
-            .collect()
+            .collect();
+        (pixels, splats)
     }
 
     #[inline]
@@ -240,21 +677,47 @@ impl Renderer {
                             (pixel % self.width) as f32,
                             (self.height - 1 - pixel / self.width) as f32,
                         );
+                        // The preview is pass zero of each pixel's stream
+                        let sampler = &mut Sampler::new(self.seed, 0, *pixel);
                         match mode {
                             Mode::Normals => normals_only(
-                                self.camera.gen_ray(self.width, self.height, x, y, jx, jy),
+                                self.camera
+                                    .gen_ray(self.width, self.height, x, y, jx, jy, sampler),
                                 &world_bvh,
                                 &hdr,
+                                sampler,
                             ),
                             Mode::Colors => colors_only(
-                                self.camera.gen_ray(self.width, self.height, x, y, jx, jy),
+                                self.camera
+                                    .gen_ray(self.width, self.height, x, y, jx, jy, sampler),
                                 &world_bvh,
                                 &hdr,
+                                sampler,
+                            ),
+                            Mode::AmbientOcclusion => {
+                                let ray = self.camera.gen_ray(
+                                    self.width,
+                                    self.height,
+                                    x,
+                                    y,
+                                    jx,
+                                    jy,
+                                    sampler,
+                                );
+                                ambient_occlusion(ray, &world_bvh, &hdr, self.ao_distance, sampler)
+                            }
+                            Mode::TraversalCost => traversal_cost(
+                                self.camera
+                                    .gen_ray(self.width, self.height, x, y, jx, jy, sampler),
+                                &world_bvh,
+                                sampler,
                             ),
                             Mode::Image => no_bounce(
-                                self.camera.gen_ray(self.width, self.height, x, y, jx, jy),
+                                self.camera
+                                    .gen_ray(self.width, self.height, x, y, jx, jy, sampler),
                                 &world_bvh,
                                 &hdr,
+                                sampler,
                             ),
                         }
                     })
@@ -263,3 +726,38 @@ impl Renderer {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIREFLIES: Fireflies = Fireflies {
+        direct_clamp: 4.0,
+        indirect_clamp: 1.0,
+        regularize: 0.0,
+    };
+
+    #[test]
+    fn clamps_by_bounce_count_keeping_the_hue() {
+        let bright = Vec3::new(8.0, 2.0, 0.0);
+        assert_eq!(FIREFLIES.clamp(bright, 0), bright);
+        assert_eq!(FIREFLIES.clamp(bright, 1), Vec3::new(4.0, 1.0, 0.0));
+        assert_eq!(FIREFLIES.clamp(bright, 2), Vec3::new(1.0, 0.25, 0.0));
+        assert_eq!(FIREFLIES.clamp(bright, 7), Vec3::new(1.0, 0.25, 0.0));
+        let dim = Vec3::new(0.5, 0.2, 0.1);
+        assert_eq!(FIREFLIES.clamp(dim, 1), dim);
+        assert_eq!(FIREFLIES.clamp(dim, 3), dim);
+    }
+
+    #[test]
+    fn infinite_clamps_change_nothing() {
+        let fireflies = Fireflies {
+            direct_clamp: f32::INFINITY,
+            indirect_clamp: f32::INFINITY,
+            regularize: 0.0,
+        };
+        let bright = Vec3::new(1e30, 5.0, 0.0);
+        assert_eq!(fireflies.clamp(bright, 1), bright);
+        assert_eq!(fireflies.clamp(bright, 4), bright);
+    }
+}
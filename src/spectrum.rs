@@ -0,0 +1,133 @@
+use ultraviolet::{Mat3, Vec3};
+
+pub const LAMBDA_MIN: f32 = 380.0;
+pub const LAMBDA_MAX: f32 = 720.0;
+const LAMBDA_RANGE: f32 = LAMBDA_MAX - LAMBDA_MIN;
+// Wavelengths traced together with the hero, the hero included
+const HERO_COUNT: usize = 3;
+
+// Smits' RGB to reflectance basis, 10 bins between LAMBDA_MIN and LAMBDA_MAX
+const BINS: usize = 10;
+const WHITE: [f32; BINS] = [
+    1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000,
+];
+const CYAN: [f32; BINS] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000,
+];
+const MAGENTA: [f32; BINS] = [
+    1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959,
+];
+const YELLOW: [f32; BINS] = [
+    0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const RED: [f32; BINS] = [
+    0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const GREEN: [f32; BINS] = [
+    0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025,
+];
+const BLUE: [f32; BINS] = [
+    1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+// Linear sRGB from CIE XYZ
+const XYZ_TO_RGB: Mat3 = Mat3::new(
+    Vec3::new(3.2404542, -0.969266, 0.0556434),
+    Vec3::new(-1.5371385, 1.8760108, -0.2040259),
+    Vec3::new(-0.4985314, 0.0415560, 1.0572252),
+);
+// Integral of the y matching curve over the traced range
+const Y_INTEGRAL: f32 = 106.91187;
+// Brings a flat spectrum back to RGB white
+const WHITE_BALANCE: Vec3 = Vec3::new(1.0 / 1.2006065, 1.0 / 0.9496375, 1.0 / 0.9079082);
+
+/// Hero wavelength and its evenly spaced companions, in nanometres.
+#[derive(Copy, Clone, Debug)]
+pub struct Wavelengths {
+    pub lambda: Vec3,
+}
+
+impl Wavelengths {
+    pub fn sample(r: f32) -> Wavelengths {
+        let hero = LAMBDA_MIN + LAMBDA_RANGE * r;
+        let step = LAMBDA_RANGE / HERO_COUNT as f32;
+        let rotate = |i: f32| LAMBDA_MIN + (hero - LAMBDA_MIN + i * step) % LAMBDA_RANGE;
+        Wavelengths {
+            lambda: Vec3::new(hero, rotate(1.0), rotate(2.0)),
+        }
+    }
+
+    #[inline]
+    pub fn hero(&self) -> f32 {
+        self.lambda.x
+    }
+
+    /// Values of the spectrum upsampled from `rgb` at each wavelength.
+    #[inline]
+    pub fn from_rgb(&self, rgb: Vec3) -> Vec3 {
+        self.lambda.map(|lambda| upsample(rgb, lambda))
+    }
+
+    /// Linear RGB estimate of a radiance sampled at these wavelengths.
+    #[inline]
+    pub fn to_rgb(&self, values: Vec3) -> Vec3 {
+        let xyz = cie_xyz(self.lambda.x) * values.x
+            + cie_xyz(self.lambda.y) * values.y
+            + cie_xyz(self.lambda.z) * values.z;
+        XYZ_TO_RGB * xyz * (LAMBDA_RANGE / (HERO_COUNT as f32 * Y_INTEGRAL)) * WHITE_BALANCE
+    }
+}
+
+#[inline]
+fn basis(table: &[f32; BINS], lambda: f32) -> f32 {
+    let x =
+        ((lambda - LAMBDA_MIN) / LAMBDA_RANGE * BINS as f32 - 0.5).clamp(0.0, (BINS - 1) as f32);
+    let i = (x as usize).min(BINS - 2);
+    let t = x - i as f32;
+    table[i] * (1.0 - t) + table[i + 1] * t
+}
+
+/// Smits' reflectance spectrum for `rgb` at a single wavelength.
+fn upsample(rgb: Vec3, lambda: f32) -> f32 {
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+    let at = |table| basis(table, lambda);
+    if r <= g && r <= b {
+        r * at(&WHITE)
+            + if g <= b {
+                (g - r) * at(&CYAN) + (b - g) * at(&BLUE)
+            } else {
+                (b - r) * at(&CYAN) + (g - b) * at(&GREEN)
+            }
+    } else if g <= r && g <= b {
+        g * at(&WHITE)
+            + if r <= b {
+                (r - g) * at(&MAGENTA) + (b - r) * at(&BLUE)
+            } else {
+                (b - g) * at(&MAGENTA) + (r - b) * at(&RED)
+            }
+    } else {
+        b * at(&WHITE)
+            + if r <= g {
+                (r - b) * at(&YELLOW) + (g - r) * at(&GREEN)
+            } else {
+                (g - b) * at(&YELLOW) + (r - g) * at(&RED)
+            }
+    }
+}
+
+#[inline]
+fn lobe(lambda: f32, mean: f32, below: f32, above: f32) -> f32 {
+    let t = (lambda - mean) / if lambda < mean { below } else { above };
+    (-0.5 * t * t).exp()
+}
+
+/// CIE 1931 matching functions, multi-lobe fit from Wyman et al.
+#[inline]
+fn cie_xyz(lambda: f32) -> Vec3 {
+    Vec3::new(
+        1.056 * lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * lobe(lambda, 442.0, 16.0, 26.7)
+            - 0.065 * lobe(lambda, 501.1, 20.4, 26.2),
+        0.821 * lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * lobe(lambda, 530.9, 16.3, 31.1),
+        1.217 * lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * lobe(lambda, 459.0, 26.0, 13.8),
+    )
+}
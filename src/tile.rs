@@ -0,0 +1,190 @@
+use clap::ValueEnum;
+
+/// Order tiles are handed out in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TileOrder {
+    /// Outwards from the centre of the frame
+    Spiral,
+    /// Along a Hilbert curve, keeping consecutive tiles close together
+    Hilbert,
+    /// Row by row from the top
+    Scanline,
+}
+
+/// Square block of the frame, in buffer coordinates with row zero at the top.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Tile {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl Tile {
+    #[inline]
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        (self.x0..self.x1).contains(&x) && (self.y0..self.y1).contains(&y)
+    }
+
+    /// Buffer indices of the tile's pixels, row by row.
+    pub fn pixels(&self, width: usize) -> impl Iterator<Item = usize> + '_ {
+        (self.y0..self.y1).flat_map(move |y| (self.x0..self.x1).map(move |x| y * width + x))
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        (self.x1 - self.x0) * (self.y1 - self.y0)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Splits a `width` by `height` frame into tiles of `size` pixels and orders them.
+pub fn tiles(width: usize, height: usize, size: usize, order: TileOrder) -> Vec<Tile> {
+    let size = size.max(1);
+    let (columns, rows) = (width.div_ceil(size), height.div_ceil(size));
+    let tile = |(column, row): (usize, usize)| Tile {
+        x0: column * size,
+        y0: row * size,
+        x1: ((column + 1) * size).min(width),
+        y1: ((row + 1) * size).min(height),
+    };
+    let cells = (0..rows).flat_map(|row| (0..columns).map(move |column| (column, row)));
+    match order {
+        TileOrder::Scanline => cells.map(tile).collect(),
+        TileOrder::Hilbert => {
+            let side = columns.max(rows).next_power_of_two();
+            let mut cells = cells.collect::<Vec<_>>();
+            cells.sort_by_key(|&(column, row)| hilbert_index(side, column, row));
+            cells.into_iter().map(tile).collect()
+        }
+        TileOrder::Spiral => spiral(columns, rows).into_iter().map(tile).collect(),
+    }
+}
+
+/// Moves the tile holding pixel `(x, y)` to the front, leaving the rest in order.
+pub fn prioritize(tiles: &mut [Tile], x: usize, y: usize) {
+    if let Some(index) = tiles.iter().position(|tile| tile.contains(x, y)) {
+        tiles[..=index].rotate_right(1);
+    }
+}
+
+/// Distance along a Hilbert curve filling a `side` by `side` grid.
+fn hilbert_index(side: usize, mut x: usize, mut y: usize) -> usize {
+    let mut index = 0;
+    let mut s = side / 2;
+    while s > 0 {
+        let rx = usize::from(x & s > 0);
+        let ry = usize::from(y & s > 0);
+        index += s * s * ((3 * rx) ^ ry);
+        // Rotate the quadrant so the curve stays continuous
+        if ry == 0 {
+            if rx == 1 {
+                x = side - 1 - x;
+                y = side - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    index
+}
+
+/// Grid cells visited by walking outwards from the centre in a square spiral.
+fn spiral(columns: usize, rows: usize) -> Vec<(usize, usize)> {
+    let mut cells = Vec::with_capacity(columns * rows);
+    let (mut x, mut y) = (((columns - 1) / 2) as isize, ((rows - 1) / 2) as isize);
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let mut leg = 1;
+    let mut turn = 0;
+    while cells.len() < columns * rows {
+        // Two legs of each length, then the spiral widens
+        for _ in 0..2 {
+            let (dx, dy) = directions[turn % 4];
+            for _ in 0..leg {
+                if (0..columns as isize).contains(&x) && (0..rows as isize).contains(&y) {
+                    cells.push((x as usize, y as usize));
+                }
+                x += dx;
+                y += dy;
+            }
+            turn += 1;
+        }
+        leg += 1;
+    }
+    cells
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hilbert_index_walks_every_cell_one_step_at_a_time() {
+        for side in [1, 2, 4, 8, 16] {
+            let mut cells = vec![None; side * side];
+            for (x, y) in (0..side).flat_map(|y| (0..side).map(move |x| (x, y))) {
+                let index = hilbert_index(side, x, y);
+                assert!(
+                    cells[index].replace((x, y)).is_none(),
+                    "index {index} used twice"
+                );
+            }
+            let cells = cells.into_iter().map(Option::unwrap).collect::<Vec<_>>();
+            for pair in cells.windows(2) {
+                let ((ax, ay), (bx, by)) = (pair[0], pair[1]);
+                assert_eq!(
+                    ax.abs_diff(bx) + ay.abs_diff(by),
+                    1,
+                    "jump in {side}: {pair:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn spiral_visits_every_cell_once_from_the_centre() {
+        for (columns, rows) in [(1, 1), (1, 6), (5, 3), (4, 7), (10, 10)] {
+            let cells = spiral(columns, rows);
+            assert_eq!(cells[0], ((columns - 1) / 2, (rows - 1) / 2));
+            let mut seen = vec![false; columns * rows];
+            for (x, y) in cells {
+                assert!(x < columns && y < rows);
+                assert!(!std::mem::replace(&mut seen[y * columns + x], true));
+            }
+            assert!(
+                seen.into_iter().all(|seen| seen),
+                "{columns}x{rows} missed a cell"
+            );
+        }
+    }
+
+    #[test]
+    fn tiles_cover_every_pixel_once() {
+        let (width, height) = (70, 45);
+        for order in [TileOrder::Spiral, TileOrder::Hilbert, TileOrder::Scanline] {
+            for size in [1, 16, 32, 100] {
+                let mut covered = vec![0; width * height];
+                for tile in tiles(width, height, size, order) {
+                    assert!(!tile.is_empty());
+                    tile.pixels(width).for_each(|pixel| covered[pixel] += 1);
+                }
+                assert!(
+                    covered.iter().all(|&count| count == 1),
+                    "{order:?} at {size}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn prioritize_moves_one_tile_to_the_front() {
+        let mut ordered = tiles(64, 64, 16, TileOrder::Scanline);
+        let expected = [&ordered[5..6], &ordered[..5], &ordered[6..]].concat();
+        prioritize(&mut ordered, 20, 17);
+        assert_eq!(ordered, expected);
+    }
+}
@@ -1,12 +1,73 @@
-use std::{cmp::Ordering, sync::Arc};
+use std::{cell::Cell, cmp::Ordering, ops::Add, ops::Sub, sync::Arc};
 
-use crate::ray::Ray;
+use crate::{random::Sampler, ray::Ray};
 
 use super::{
     cube::Aabb,
     hittable::{HitRecord, Hittable},
 };
 
+thread_local! {
+    static STATS: Cell<TraversalStats> = const { Cell::new(TraversalStats::zero()) };
+}
+
+/// Traversal work counted on one thread.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraversalStats {
+    pub rays: u64,
+    pub box_tests: u64,
+    pub primitive_tests: u64,
+}
+
+impl TraversalStats {
+    pub const fn zero() -> TraversalStats {
+        TraversalStats {
+            rays: 0,
+            box_tests: 0,
+            primitive_tests: 0,
+        }
+    }
+
+    /// Running totals for the calling thread, subtract two readings to measure a stretch of work.
+    #[inline]
+    pub fn current() -> TraversalStats {
+        STATS.with(Cell::get)
+    }
+
+    #[inline]
+    fn count(update: impl FnOnce(&mut TraversalStats)) {
+        STATS.with(|stats| {
+            let mut value = stats.get();
+            update(&mut value);
+            stats.set(value);
+        });
+    }
+}
+
+impl Add for TraversalStats {
+    type Output = TraversalStats;
+
+    fn add(self, other: TraversalStats) -> TraversalStats {
+        TraversalStats {
+            rays: self.rays + other.rays,
+            box_tests: self.box_tests + other.box_tests,
+            primitive_tests: self.primitive_tests + other.primitive_tests,
+        }
+    }
+}
+
+impl Sub for TraversalStats {
+    type Output = TraversalStats;
+
+    fn sub(self, other: TraversalStats) -> TraversalStats {
+        TraversalStats {
+            rays: self.rays - other.rays,
+            box_tests: self.box_tests - other.box_tests,
+            primitive_tests: self.primitive_tests - other.primitive_tests,
+        }
+    }
+}
+
 pub enum BvhNode {
     Branch(Arc<Bvh>),
     Leaf(Arc<dyn Hittable + Send + Sync>),
@@ -20,10 +81,19 @@ impl Hittable for BvhNode {
         }
     }
 
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>> {
         match self {
-            BvhNode::Branch(branch) => branch.hit(ray, t_min, t_max),
-            BvhNode::Leaf(leaf) => leaf.hit(ray, t_min, t_max),
+            BvhNode::Branch(branch) => branch.traverse(ray, t_min, t_max, sampler),
+            BvhNode::Leaf(leaf) => {
+                TraversalStats::count(|stats| stats.primitive_tests += 1);
+                leaf.hit(ray, t_min, t_max, sampler)
+            }
         }
     }
 }
@@ -139,8 +209,17 @@ impl Bvh {
     }
 }
 
-impl Hittable for Bvh {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+impl Bvh {
+    /// Closest hit without counting a ray, for trees inside a primitive like a mesh, which the
+    /// ray reached through the scene's tree.
+    pub fn traverse(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>> {
+        TraversalStats::count(|stats| stats.box_tests += 1);
         if self
             .aabb_box
             .hit(ray.pos, ray.dir.map(|k| k.recip()), t_min, t_max)
@@ -148,10 +227,10 @@ impl Hittable for Bvh {
             let right_hit = self
                 .right
                 .as_ref()
-                .and_then(|child_right| child_right.hit(ray, t_min, t_max));
+                .and_then(|child_right| child_right.hit(ray, t_min, t_max, sampler));
 
             let left_hit = self.left.as_ref().and_then(|child_left| {
-                child_left.hit(ray, t_min, right_hit.map_or(t_max, |hit| hit.t))
+                child_left.hit(ray, t_min, right_hit.map_or(t_max, |hit| hit.t), sampler)
             });
 
             left_hit.or(right_hit)
@@ -159,6 +238,19 @@ impl Hittable for Bvh {
             None
         }
     }
+}
+
+impl Hittable for Bvh {
+    fn hit(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>> {
+        TraversalStats::count(|stats| stats.rays += 1);
+        self.traverse(ray, t_min, t_max, sampler)
+    }
 
     #[inline]
     fn bounding_box(&self) -> Aabb {
@@ -1,12 +1,12 @@
 use std::f32::consts::PI;
 
-use ultraviolet::{Rotor3, Vec3};
+use ultraviolet::{Rotor3, Vec2, Vec3};
 
-use crate::{material::Material, ray::Ray};
+use crate::{material::Material, random::Sampler, ray::Ray};
 
-use super::hittable::{HitRecord, Hittable};
+use super::hittable::{HitRecord, Hittable, Uv};
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct ABox {
     pub min: Vec3,
     pub max: Vec3,
@@ -15,6 +15,14 @@ pub struct ABox {
 }
 
 const TOLERANCE: f32 = 0.0001;
+const FACES: [(usize, f32); 6] = [
+    (0, 1.0),
+    (0, -1.0),
+    (1, 1.0),
+    (1, -1.0),
+    (2, 1.0),
+    (2, -1.0),
+];
 
 impl ABox {
     pub fn new(center: (f32, f32, f32), size: (f32, f32, f32), mat: Material) -> ABox {
@@ -36,10 +44,88 @@ impl ABox {
             material: mat,
         }
     }
+
+    #[inline]
+    fn contains(&self, point: Vec3) -> bool {
+        (0..3).all(|axis| point[axis] >= self.min[axis] && point[axis] <= self.max[axis])
+    }
+
+    #[inline]
+    fn face_area(&self, axis: usize) -> f32 {
+        let size = self.max - self.min;
+        size[(axis + 1) % 3] * size[(axis + 2) % 3]
+    }
+
+    /// Whether the face on `side` of `axis` faces `origin`. From inside the box every face is visible.
+    #[inline]
+    fn sees_face(&self, origin: Vec3, axis: usize, side: f32) -> bool {
+        self.contains(origin)
+            || if side > 0.0 {
+                origin[axis] > self.max[axis]
+            } else {
+                origin[axis] < self.min[axis]
+            }
+    }
+
+    /// Point at `(u, v)` across the face on `side` of `axis`.
+    #[inline]
+    fn face_point(&self, axis: usize, side: f32, u: f32, v: f32) -> Vec3 {
+        let size = self.max - self.min;
+        let (u_axis, v_axis) = ((axis + 1) % 3, (axis + 2) % 3);
+        let mut point = self.min;
+        point[axis] = if side > 0.0 {
+            self.max[axis]
+        } else {
+            self.min[axis]
+        };
+        point[u_axis] += u * size[u_axis];
+        point[v_axis] += v * size[v_axis];
+        point
+    }
+
+    /// Coordinates across the face with `normal`, laid out as in `face_point`.
+    #[inline]
+    fn uv(&self, point: Vec3, normal: Vec3) -> Uv {
+        let axis = if normal.x != 0.0 {
+            0
+        } else if normal.y != 0.0 {
+            1
+        } else {
+            2
+        };
+        let size = self.max - self.min;
+        let (u_axis, v_axis) = ((axis + 1) % 3, (axis + 2) % 3);
+        let (mut dpdu, mut dpdv) = (Vec3::zero(), Vec3::zero());
+        dpdu[u_axis] = size[u_axis];
+        dpdv[v_axis] = size[v_axis];
+        Uv {
+            point: Vec2::new(
+                (point[u_axis] - self.min[u_axis]) / size[u_axis],
+                (point[v_axis] - self.min[v_axis]) / size[v_axis],
+            ),
+            dpdu,
+            dpdv,
+            ..Uv::default()
+        }
+    }
+
+    fn visible_area(&self, origin: Vec3) -> f32 {
+        FACES
+            .iter()
+            .filter(|(axis, side)| self.sees_face(origin, *axis, *side))
+            .map(|(axis, _)| self.face_area(*axis))
+            .sum()
+    }
 }
 
 impl Hittable for ABox {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        _sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>> {
         let inv_d = ray.dir.map(|k| k.recip());
         let t0 = (self.min - ray.pos) * inv_d;
         let t1 = (self.max - ray.pos) * inv_d;
@@ -65,7 +151,7 @@ impl Hittable for ABox {
                 _ => Vec3::zero(),
             } * if self.hollow { -1.0 } else { 1.0 };
 
-            Some(HitRecord::new(t, p, normal, &self.material))
+            Some(HitRecord::new(t, p, normal, &self.material).with_uv(self.uv(p, normal)))
         } else {
             None
         }
@@ -77,9 +163,60 @@ impl Hittable for ABox {
             max: self.max,
         }
     }
+
+    fn pdf_value(&self, origin: Vec3, dir: Vec3) -> f32 {
+        let ray = Ray::new(origin, dir);
+        // A box never draws from the sampler
+        match self.hit(&ray, TOLERANCE, f32::MAX, &mut Sampler::from_seed(0)) {
+            Some(hit) if hit.normal != Vec3::zero() => {
+                let cosine = hit.normal.dot(ray.dir).abs();
+                hit.t.powi(2) / (cosine * self.visible_area(origin))
+            }
+            _ => 0.0,
+        }
+    }
+
+    fn random(&self, origin: Vec3, r1: f32, r2: f32) -> Vec3 {
+        // Pick one of the faces facing the origin by area, then a point on it
+        let mut pick = r1 * self.visible_area(origin);
+        for (axis, side) in FACES {
+            if !self.sees_face(origin, axis, side) {
+                continue;
+            }
+            let area = self.face_area(axis);
+            if area > 0.0 && pick <= area {
+                return self.face_point(axis, side, pick / area, r2) - origin;
+            }
+            pick -= area;
+        }
+        self.bounding_box().center() - origin
+    }
+
+    fn area(&self) -> f32 {
+        FACES.iter().map(|(axis, _)| self.face_area(*axis)).sum()
+    }
+
+    fn sample_surface(&self, r1: f32, r2: f32) -> Option<HitRecord<'_>> {
+        let mut pick = r1 * self.area();
+        for (axis, side) in FACES {
+            let area = self.face_area(axis);
+            if area > 0.0 && pick <= area {
+                let mut normal = Vec3::zero();
+                normal[axis] = if self.hollow { -side } else { side };
+                return Some(HitRecord::new(
+                    0.0,
+                    self.face_point(axis, side, pick / area, r2),
+                    normal,
+                    &self.material,
+                ));
+            }
+            pick -= area;
+        }
+        None
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct Cube {
     pub axis_box: ABox,
     center: Vec3,
@@ -103,17 +240,24 @@ impl Cube {
 }
 
 impl Hittable for Cube {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>> {
         let rot_pos = (ray.pos - self.center).rotated_by(self.rotation.reversed()) + self.center;
         let rot_dir = ray.dir.rotated_by(self.rotation.reversed());
 
         self.axis_box
-            .hit(&Ray::new(rot_pos, rot_dir), t_min, t_max)
+            .hit(&Ray::new(rot_pos, rot_dir), t_min, t_max, sampler)
             .map(|hit| HitRecord {
                 t: hit.t,
                 point: ray.at(hit.t),
                 normal: hit.normal.rotated_by(self.rotation),
-                material: hit.material,
+                uv: hit.uv.rotated_by(self.rotation),
+                ..hit
             })
     }
 
@@ -146,6 +290,32 @@ impl Hittable for Cube {
         );
         Aabb { min, max }
     }
+
+    fn pdf_value(&self, origin: Vec3, dir: Vec3) -> f32 {
+        let rot_origin = (origin - self.center).rotated_by(self.rotation.reversed()) + self.center;
+        self.axis_box
+            .pdf_value(rot_origin, dir.rotated_by(self.rotation.reversed()))
+    }
+
+    fn random(&self, origin: Vec3, r1: f32, r2: f32) -> Vec3 {
+        let rot_origin = (origin - self.center).rotated_by(self.rotation.reversed()) + self.center;
+        self.axis_box
+            .random(rot_origin, r1, r2)
+            .rotated_by(self.rotation)
+    }
+
+    fn area(&self) -> f32 {
+        self.axis_box.area()
+    }
+
+    fn sample_surface(&self, r1: f32, r2: f32) -> Option<HitRecord<'_>> {
+        self.axis_box.sample_surface(r1, r2).map(|hit| HitRecord {
+            point: (hit.point - self.center).rotated_by(self.rotation) + self.center,
+            normal: hit.normal.rotated_by(self.rotation),
+            uv: hit.uv.rotated_by(self.rotation),
+            ..hit
+        })
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
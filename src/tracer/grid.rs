@@ -0,0 +1,214 @@
+use ultraviolet::Vec3;
+
+use crate::{material::Material, random::Sampler, ray::Ray};
+
+use super::{
+    cube::Aabb,
+    hittable::{HitRecord, Hittable},
+};
+
+// Voxels per side of each majorant cell
+const BLOCK: usize = 8;
+
+/// Voxel densities as stored on disk, with x varying fastest.
+#[derive(Debug, Clone)]
+pub struct DensityGrid {
+    pub resolution: [usize; 3],
+    pub min: Vec3,
+    pub max: Vec3,
+    pub density: Vec<f32>,
+}
+
+/// Smoke or clouds from a dense voxel grid of densities, tracked with a coarse grid of majorants.
+#[derive(Clone)]
+pub struct VolumeGrid {
+    resolution: [usize; 3],
+    min: Vec3,
+    max: Vec3,
+    voxel_size: Vec3,
+    density: Vec<f32>,
+    coarse_resolution: [usize; 3],
+    majorants: Vec<f32>,
+    extinction: f32,
+    pub phase: Material,
+}
+
+impl VolumeGrid {
+    pub fn new(
+        grid: DensityGrid,
+        absorption: f32,
+        scattering: f32,
+        color: (f32, f32, f32),
+        anisotropy: f32,
+    ) -> VolumeGrid {
+        let DensityGrid {
+            resolution,
+            min,
+            max,
+            density,
+        } = grid;
+        let voxel_size = (max - min)
+            / Vec3::new(
+                resolution[0] as f32,
+                resolution[1] as f32,
+                resolution[2] as f32,
+            );
+        let coarse_resolution = resolution.map(|r| r.div_ceil(BLOCK));
+        let extinction = (absorption + scattering).max(0.0);
+        let albedo = if extinction > 0.0 {
+            scattering.max(0.0) / extinction
+        } else {
+            0.0
+        };
+
+        let mut grid = VolumeGrid {
+            resolution,
+            min,
+            max,
+            voxel_size,
+            density,
+            coarse_resolution,
+            majorants: vec![],
+            extinction,
+            phase: Material::henyey_greenstein(
+                (color.0 * albedo, color.1 * albedo, color.2 * albedo),
+                anisotropy,
+            ),
+        };
+        grid.majorants = grid.build_majorants();
+        grid
+    }
+
+    /// Maximum density over every voxel that can influence each coarse cell,
+    /// including the one voxel border trilinear filtering reaches into.
+    fn build_majorants(&self) -> Vec<f32> {
+        let [cx, cy, cz] = self.coarse_resolution;
+        let range = |c: usize, axis: usize| {
+            (c * BLOCK).saturating_sub(1)..((c + 1) * BLOCK + 1).min(self.resolution[axis])
+        };
+        (0..cx * cy * cz)
+            .map(|index| {
+                let (x, y, z) = (index % cx, (index / cx) % cy, index / (cx * cy));
+                let mut majorant: f32 = 0.0;
+                for k in range(z, 2) {
+                    for j in range(y, 1) {
+                        for i in range(x, 0) {
+                            majorant = majorant.max(self.voxel(i, j, k));
+                        }
+                    }
+                }
+                majorant
+            })
+            .collect()
+    }
+
+    #[inline]
+    fn voxel(&self, x: usize, y: usize, z: usize) -> f32 {
+        self.density[(z * self.resolution[1] + y) * self.resolution[0] + x]
+    }
+
+    /// Trilinearly filtered density at a world position.
+    fn density_at(&self, point: Vec3) -> f32 {
+        let grid = (point - self.min) / self.voxel_size - Vec3::one() * 0.5;
+        let mut base = [0usize; 3];
+        let mut frac = [0.0f32; 3];
+        for axis in 0..3 {
+            let limit = (self.resolution[axis] - 1) as f32;
+            let g = grid[axis].clamp(0.0, limit);
+            base[axis] = (g.floor() as usize).min(self.resolution[axis].saturating_sub(2));
+            frac[axis] = g - base[axis] as f32;
+        }
+        let next = |axis: usize| (base[axis] + 1).min(self.resolution[axis] - 1);
+
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let (x0, x1, y0, y1, z0, z1) = (base[0], next(0), base[1], next(1), base[2], next(2));
+        let c00 = lerp(self.voxel(x0, y0, z0), self.voxel(x1, y0, z0), frac[0]);
+        let c10 = lerp(self.voxel(x0, y1, z0), self.voxel(x1, y1, z0), frac[0]);
+        let c01 = lerp(self.voxel(x0, y0, z1), self.voxel(x1, y0, z1), frac[0]);
+        let c11 = lerp(self.voxel(x0, y1, z1), self.voxel(x1, y1, z1), frac[0]);
+        lerp(lerp(c00, c10, frac[1]), lerp(c01, c11, frac[1]), frac[2])
+    }
+}
+
+impl Hittable for VolumeGrid {
+    fn hit(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>> {
+        if self.extinction <= 0.0 {
+            return None;
+        }
+        let inv_d = ray.dir.map(|k| k.recip());
+        let t0 = (self.min - ray.pos) * inv_d;
+        let t1 = (self.max - ray.pos) * inv_d;
+        let t_enter = t0.min_by_component(t1).component_max().max(t_min);
+        let t_exit = t0.max_by_component(t1).component_min().min(t_max);
+        if t_enter >= t_exit {
+            return None;
+        }
+
+        // Walk the coarse cells along the ray, delta tracking against each cell's majorant
+        let cell_size = self.voxel_size * BLOCK as f32;
+        let start = (ray.at(t_enter) - self.min) / cell_size;
+        let mut cell = [0i64; 3];
+        let mut step = [0i64; 3];
+        let mut t_next = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+        for axis in 0..3 {
+            cell[axis] =
+                (start[axis].floor() as i64).clamp(0, self.coarse_resolution[axis] as i64 - 1);
+            if ray.dir[axis] != 0.0 {
+                step[axis] = if ray.dir[axis] > 0.0 { 1 } else { -1 };
+                let boundary = self.min[axis]
+                    + (cell[axis] + (step[axis] > 0) as i64) as f32 * cell_size[axis];
+                t_next[axis] = (boundary - ray.pos[axis]) * inv_d[axis];
+                t_delta[axis] = (cell_size[axis] * inv_d[axis]).abs();
+            }
+        }
+
+        let mut t = t_enter;
+        loop {
+            let axis = if t_next[0] < t_next[1] && t_next[0] < t_next[2] {
+                0
+            } else if t_next[1] < t_next[2] {
+                1
+            } else {
+                2
+            };
+            let cell_end = t_next[axis].min(t_exit);
+            let index = (cell[2] as usize * self.coarse_resolution[1] + cell[1] as usize)
+                * self.coarse_resolution[0]
+                + cell[0] as usize;
+            let majorant = self.majorants[index];
+            if majorant > 0.0 {
+                loop {
+                    t -= (1.0 - sampler.get_1d()).ln() / (majorant * self.extinction);
+                    if t >= cell_end {
+                        break;
+                    }
+                    // Real collision, otherwise a null one and tracking carries on
+                    let point = ray.at(t);
+                    if sampler.get_1d() * majorant < self.density_at(point) {
+                        return Some(HitRecord::new(t, point, -ray.dir, &self.phase));
+                    }
+                }
+            }
+            if cell_end >= t_exit {
+                return None;
+            }
+            t = cell_end;
+            cell[axis] += step[axis];
+            if cell[axis] < 0 || cell[axis] >= self.coarse_resolution[axis] as i64 {
+                return None;
+            }
+            t_next[axis] += t_delta[axis];
+        }
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        Aabb::new(self.min, self.max)
+    }
+}
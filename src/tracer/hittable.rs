@@ -1,13 +1,41 @@
-use crate::{material::Material, ray::Ray};
+use crate::{material::Material, random::Sampler, ray::Ray};
 
-use ultraviolet::Vec3;
+use ultraviolet::{Rotor3, Vec2, Vec3};
 
 use super::cube::Aabb;
 
 pub trait Hittable {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+    fn hit(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>>;
 
     fn bounding_box(&self) -> Aabb;
+
+    /// Solid angle density of `random` choosing `dir` when looking from `origin`.
+    /// Shapes that can't be sampled report zero.
+    fn pdf_value(&self, _origin: Vec3, _dir: Vec3) -> f32 {
+        0.0
+    }
+
+    /// Direction from `origin` towards a random point on the shape.
+    fn random(&self, _origin: Vec3, _r1: f32, _r2: f32) -> Vec3 {
+        Vec3::unit_y()
+    }
+
+    /// Surface area, for picking points on the shape uniformly.
+    fn area(&self) -> f32 {
+        0.0
+    }
+
+    /// Point picked uniformly by area, as a hit with the shape's outward normal.
+    /// Shapes that can't be sampled give `None`.
+    fn sample_surface(&self, _r1: f32, _r2: f32) -> Option<HitRecord<'_>> {
+        None
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -16,15 +44,126 @@ pub struct HitRecord<'a> {
     pub point: Vec3,
     pub normal: Vec3,
     pub material: &'a Material,
+    /// Scene object and material the hit belongs to, counted from one. Zero when untagged.
+    pub object_index: u32,
+    pub material_index: u32,
+    pub uv: Uv,
+}
+
+/// Texture coordinates of a hit, and how the point and its normal change along them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Uv {
+    pub point: Vec2,
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    pub dndu: Vec3,
+    pub dndv: Vec3,
+}
+
+impl Uv {
+    #[inline]
+    pub fn rotated_by(self, rotation: Rotor3) -> Uv {
+        Uv {
+            dpdu: self.dpdu.rotated_by(rotation),
+            dpdv: self.dpdv.rotated_by(rotation),
+            dndu: self.dndu.rotated_by(rotation),
+            dndv: self.dndv.rotated_by(rotation),
+            ..self
+        }
+    }
+}
+
+/// What one pixel covers around a hit: how far the point moves across the surface, and
+/// through uv space, a pixel over in x and in y.
+#[derive(Clone, Copy, Debug)]
+pub struct Footprint {
+    pub dpdx: Vec3,
+    pub dpdy: Vec3,
+    pub duvdx: Vec2,
+    pub duvdy: Vec2,
+}
+
+impl Footprint {
+    /// Longest side of the footprint in uv space.
+    #[inline]
+    pub fn width(&self) -> f32 {
+        self.duvdx.mag().max(self.duvdy.mag())
+    }
+
+    /// Mip level that keeps a texture `size` texels across from aliasing, zero being full size.
+    #[inline]
+    pub fn mip_level(&self, size: f32) -> f32 {
+        (self.width() * size).max(1.0).log2()
+    }
+
+    /// How the normal turns a pixel over in x and in y.
+    #[inline]
+    pub fn normal_steps(&self, uv: Uv) -> (Vec3, Vec3) {
+        (
+            uv.dndu * self.duvdx.x + uv.dndv * self.duvdx.y,
+            uv.dndu * self.duvdy.x + uv.dndv * self.duvdy.y,
+        )
+    }
 }
 
 impl HitRecord<'_> {
-    pub fn new(t: f32, point: Vec3, normal: Vec3, material: &Material) -> HitRecord {
+    pub fn new(t: f32, point: Vec3, normal: Vec3, material: &Material) -> HitRecord<'_> {
         HitRecord {
             t,
             point,
             normal,
             material,
+            object_index: 0,
+            material_index: 0,
+            uv: Uv::default(),
+        }
+    }
+
+    #[inline]
+    pub fn with_uv(self, uv: Uv) -> Self {
+        HitRecord { uv, ..self }
+    }
+
+    /// Footprint of a pixel around the hit, from where the differentials of `ray` cross the
+    /// tangent plane. `None` when the ray carries none or they run along the surface.
+    pub fn footprint(&self, ray: &Ray) -> Option<Footprint> {
+        let differentials = ray.differentials?;
+        let normal = self.normal;
+        let plane = normal.dot(self.point);
+        let across = |pos: Vec3, dir: Vec3| {
+            pos + dir * ((plane - normal.dot(pos)) / normal.dot(dir)) - self.point
+        };
+        let dpdx = across(differentials.x_pos, differentials.x_dir);
+        let dpdy = across(differentials.y_pos, differentials.y_dir);
+        if !(dpdx.mag_sq() + dpdy.mag_sq()).is_finite() {
+            return None;
         }
+        // Least squares onto dpdu and dpdv, over the two axes the normal leans along least
+        let n = normal.map(f32::abs);
+        let (a, b) = if n.x > n.y && n.x > n.z {
+            (1, 2)
+        } else if n.y > n.z {
+            (0, 2)
+        } else {
+            (0, 1)
+        };
+        let (dpdu, dpdv) = (self.uv.dpdu, self.uv.dpdv);
+        let det = dpdu[a] * dpdv[b] - dpdv[a] * dpdu[b];
+        let solve = |dp: Vec3| {
+            if det.abs() < 1e-12 {
+                Vec2::zero()
+            } else {
+                Vec2::new(
+                    (dp[a] * dpdv[b] - dpdv[a] * dp[b]) / det,
+                    (dpdu[a] * dp[b] - dp[a] * dpdu[b]) / det,
+                )
+            }
+        };
+        Some(Footprint {
+            dpdx,
+            dpdy,
+            duvdx: solve(dpdx),
+            duvdy: solve(dpdy),
+        })
     }
 }
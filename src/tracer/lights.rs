@@ -0,0 +1,151 @@
+use std::sync::Arc;
+
+use ultraviolet::Vec3;
+
+use crate::{random::Sampler, ray::Ray};
+
+use super::hittable::{HitRecord, Hittable};
+
+// Relative slack when matching a point to the light surface it lies on
+const TOLERANCE: f32 = 0.001;
+
+/// Emissive objects of a scene that can be sampled directly.
+#[derive(Clone, Default)]
+pub struct Lights {
+    objects: Vec<Arc<dyn Hittable + Send + Sync>>,
+}
+
+impl Lights {
+    pub fn new() -> Lights {
+        Lights {
+            objects: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, object: Arc<dyn Hittable + Send + Sync>) {
+        self.objects.push(object);
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// Density of `random` choosing `dir`, averaged over every light.
+    pub fn pdf_value(&self, origin: Vec3, dir: Vec3) -> f32 {
+        if self.objects.is_empty() {
+            return 0.0;
+        }
+        self.objects
+            .iter()
+            .map(|light| light.pdf_value(origin, dir))
+            .sum::<f32>()
+            / self.objects.len() as f32
+    }
+
+    /// Direction towards one of the lights, picked uniformly.
+    pub fn random(&self, origin: Vec3, r1: f32, r2: f32) -> Vec3 {
+        let scaled = r1 * self.objects.len() as f32;
+        let index = (scaled as usize).min(self.objects.len() - 1);
+        self.objects[index].random(origin, scaled - index as f32, r2)
+    }
+
+    /// Point on one of the lights, picked uniformly by area after choosing the light
+    /// uniformly, with its area density.
+    pub fn sample_surface(&self, r1: f32, r2: f32) -> Option<(HitRecord<'_>, f32)> {
+        if self.objects.is_empty() {
+            return None;
+        }
+        let scaled = r1 * self.objects.len() as f32;
+        let index = (scaled as usize).min(self.objects.len() - 1);
+        let light = &self.objects[index];
+        let area = light.area();
+        if area <= 0.0 {
+            return None;
+        }
+        light
+            .sample_surface(scaled - index as f32, r2)
+            .map(|hit| (hit, (area * self.objects.len() as f32).recip()))
+    }
+
+    /// Area density of `sample_surface` picking `point`, the first hit along `dir` from `origin`.
+    pub fn surface_pdf(&self, origin: Vec3, dir: Vec3, point: Vec3) -> f32 {
+        if self.objects.is_empty() {
+            return 0.0;
+        }
+        let ray = Ray::new(origin, dir);
+        let distance = (point - origin).mag();
+        // Lights are surfaces, which never draw from the sampler
+        let sampler = &mut Sampler::from_seed(0);
+        self.objects
+            .iter()
+            .filter(|light| light.area() > 0.0)
+            .filter(|light| {
+                light
+                    .hit(&ray, 0.0001, f32::MAX, sampler)
+                    .is_some_and(|hit| (hit.t - distance).abs() < TOLERANCE * distance.max(1.0))
+            })
+            .map(|light| light.area().recip())
+            .sum::<f32>()
+            / self.objects.len() as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use crate::{
+        material::Material,
+        random::quasirandom_on_uniform_sphere,
+        tracer::{sphere::Sphere, triangle::Triangle},
+    };
+
+    use super::*;
+
+    fn lights() -> Lights {
+        let light = Material::emissive((1.0, 1.0, 1.0), 1.0, true);
+        let mut lights = Lights::new();
+        lights.push(Arc::new(Sphere::new((0.0, 4.0, 0.0), 1.5, light.clone())));
+        lights.push(Arc::new(Triangle::new(
+            [
+                Vec3::new(-2.0, -1.0, 3.0),
+                Vec3::new(2.0, -1.0, 3.0),
+                Vec3::new(0.0, 2.0, 3.0),
+            ],
+            [Vec3::unit_z() * -1.0; 3],
+            true,
+            light,
+        )));
+        lights
+    }
+
+    #[test]
+    fn pdf_integrates_to_one_over_directions() {
+        let lights = lights();
+        let mut sampler = Sampler::from_seed(1);
+        let count = 200_000;
+        let total: f32 = (0..count)
+            .map(|_| {
+                let (r1, r2) = sampler.get_2d();
+                lights.pdf_value(Vec3::zero(), quasirandom_on_uniform_sphere(r1, r2))
+            })
+            .sum();
+        let integral = total * 4.0 * PI / count as f32;
+        assert!((integral - 1.0).abs() < 0.05, "integral {integral}");
+    }
+
+    #[test]
+    fn sampled_directions_have_density() {
+        let lights = lights();
+        let mut sampler = Sampler::from_seed(2);
+        for _ in 0..1000 {
+            let (r1, r2) = sampler.get_2d();
+            let dir = lights.random(Vec3::zero(), r1, r2).normalized();
+            assert!(
+                lights.pdf_value(Vec3::zero(), dir) > 0.0,
+                "no density along {dir:?}"
+            );
+        }
+    }
+}
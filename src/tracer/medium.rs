@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+use crate::{material::Material, random::Sampler, ray::Ray};
+
+use super::{
+    cube::Aabb,
+    hittable::{HitRecord, Hittable},
+};
+
+// Gap left between boundary crossings so the same surface isn't found twice
+const EPSILON: f32 = 0.0001;
+
+/// Fog with constant density filling a closed boundary.
+#[derive(Clone)]
+pub struct ConstantMedium {
+    boundary: Arc<dyn Hittable + Send + Sync>,
+    extinction: f32,
+    pub phase: Material,
+}
+
+impl ConstantMedium {
+    pub fn new(
+        boundary: Arc<dyn Hittable + Send + Sync>,
+        absorption: f32,
+        scattering: f32,
+        color: (f32, f32, f32),
+        anisotropy: f32,
+    ) -> ConstantMedium {
+        let extinction = (absorption + scattering).max(0.0);
+        let albedo = if extinction > 0.0 {
+            scattering.max(0.0) / extinction
+        } else {
+            0.0
+        };
+        ConstantMedium {
+            boundary,
+            extinction,
+            phase: Material::henyey_greenstein(
+                (color.0 * albedo, color.1 * albedo, color.2 * albedo),
+                anisotropy,
+            ),
+        }
+    }
+}
+
+impl Hittable for ConstantMedium {
+    fn hit(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>> {
+        if self.extinction <= 0.0 {
+            return None;
+        }
+        // Free-flight distance, spent across each stretch of the ray inside the boundary
+        let mut distance = -(1.0 - sampler.get_1d()).ln() / self.extinction;
+        let mut t = f32::NEG_INFINITY;
+        while let Some(enter) = self.boundary.hit(ray, t, f32::INFINITY, sampler) {
+            let exit = self
+                .boundary
+                .hit(ray, enter.t + EPSILON, f32::INFINITY, sampler)?;
+            let (start, end) = (enter.t.max(t_min), exit.t.min(t_max));
+            if start >= t_max {
+                return None;
+            }
+            if start < end {
+                if distance < end - start {
+                    let t_hit = start + distance;
+                    return Some(HitRecord::new(t_hit, ray.at(t_hit), -ray.dir, &self.phase));
+                }
+                distance -= end - start;
+            }
+            t = exit.t + EPSILON;
+        }
+        None
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.boundary.bounding_box()
+    }
+}
@@ -1,8 +1,8 @@
 use std::{ops::Mul, sync::Arc};
 
-use crate::{material::Material, ray::Ray};
+use crate::{material::Material, random::Sampler, ray::Ray};
 
-use ultraviolet::{Rotor3, Vec3};
+use ultraviolet::{Rotor3, Vec2, Vec3};
 
 use super::{
     bvh::Bvh,
@@ -32,17 +32,17 @@ impl Mesh {
         polygons.indices.chunks_exact(3).for_each(|face| {
             let vertices: [Vec3; 3] = [
                 Vec3::new(
-                    polygons.positions[(3 * face[0] as usize)],
+                    polygons.positions[3 * face[0] as usize],
                     polygons.positions[(3 * face[0] as usize) + 1],
                     polygons.positions[(3 * face[0] as usize) + 2],
                 ),
                 Vec3::new(
-                    polygons.positions[(3 * face[1] as usize)],
+                    polygons.positions[3 * face[1] as usize],
                     polygons.positions[(3 * face[1] as usize) + 1],
                     polygons.positions[(3 * face[1] as usize) + 2],
                 ),
                 Vec3::new(
-                    polygons.positions[(3 * face[2] as usize)],
+                    polygons.positions[3 * face[2] as usize],
                     polygons.positions[(3 * face[2] as usize) + 1],
                     polygons.positions[(3 * face[2] as usize) + 2],
                 ),
@@ -55,29 +55,33 @@ impl Mesh {
 
             let mut normals: [Vec3; 3] = [
                 Vec3::new(
-                    polygons.normals[(3 * face[0] as usize)],
+                    polygons.normals[3 * face[0] as usize],
                     polygons.normals[(3 * face[0] as usize) + 1],
                     polygons.normals[(3 * face[0] as usize) + 2],
                 ),
                 Vec3::new(
-                    polygons.normals[(3 * face[1] as usize)],
+                    polygons.normals[3 * face[1] as usize],
                     polygons.normals[(3 * face[1] as usize) + 1],
                     polygons.normals[(3 * face[1] as usize) + 2],
                 ),
                 Vec3::new(
-                    polygons.normals[(3 * face[2] as usize)],
+                    polygons.normals[3 * face[2] as usize],
                     polygons.normals[(3 * face[2] as usize) + 1],
                     polygons.normals[(3 * face[2] as usize) + 2],
                 ),
             ];
             rot.rotate_vecs(&mut normals);
 
-            mesh.push(Arc::new(Triangle::new(
-                vertices,
-                normals,
-                !cull_backface,
-                material,
-            )));
+            let mut triangle = Triangle::new(vertices, normals, !cull_backface, material.clone());
+            if !polygons.texcoords.is_empty() {
+                triangle = triangle.with_uvs([0, 1, 2].map(|corner| {
+                    Vec2::new(
+                        polygons.texcoords[2 * face[corner] as usize],
+                        polygons.texcoords[(2 * face[corner] as usize) + 1],
+                    )
+                }));
+            }
+            mesh.push(Arc::new(triangle));
         });
         Mesh {
             bvh: Bvh::new(mesh.as_mut_slice()),
@@ -88,8 +92,14 @@ impl Mesh {
 }
 
 impl Hittable for Mesh {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        self.bvh.hit(ray, t_min, t_max)
+    fn hit(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>> {
+        self.bvh.traverse(ray, t_min, t_max, sampler)
     }
 
     fn bounding_box(&self) -> Aabb {
@@ -1,6 +1,11 @@
 pub mod bvh;
 pub mod cube;
+pub mod grid;
 pub mod hittable;
+pub mod lights;
+pub mod medium;
 pub mod mesh;
+pub mod moving;
 pub mod sphere;
+pub mod tagged;
 pub mod triangle;
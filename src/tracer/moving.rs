@@ -0,0 +1,133 @@
+use std::sync::Arc;
+
+use ultraviolet::{Bivec3, Rotor3, Vec3};
+
+use crate::{random::Sampler, ray::Ray};
+
+use super::{
+    cube::Aabb,
+    hittable::{HitRecord, Hittable},
+};
+
+// Times the motion is checked at when bounding it
+const BOUND_STEPS: usize = 16;
+
+/// Moves the wrapped shape from a start to an end transform over the motion, turning it in
+/// `plane` about `pivot` and sliding it in a straight line. The angle is lerped, so a turn can
+/// go any way round and as far as it likes. Rays are taken into the shape's own space at
+/// their time, so anything the shape does still works while it moves.
+pub struct Moving {
+    shape: Arc<dyn Hittable + Send + Sync>,
+    pivot: Vec3,
+    plane: Bivec3,
+    start: (Vec3, f32),
+    end: (Vec3, f32),
+    bounds: Aabb,
+}
+
+impl Moving {
+    pub fn new(
+        shape: Arc<dyn Hittable + Send + Sync>,
+        pivot: Vec3,
+        plane: Bivec3,
+        start: (Vec3, f32),
+        end: (Vec3, f32),
+    ) -> Moving {
+        let mut moving = Moving {
+            shape,
+            pivot,
+            plane,
+            start,
+            end,
+            bounds: Aabb::new(Vec3::zero(), Vec3::zero()),
+        };
+        moving.bounds = moving.sweep();
+        moving
+    }
+
+    /// Translation and rotation at `time`.
+    #[inline]
+    fn at(&self, time: f32) -> (Vec3, Rotor3) {
+        (
+            self.start.0 + (self.end.0 - self.start.0) * time,
+            Rotor3::from_angle_plane(
+                self.start.1 + (self.end.1 - self.start.1) * time,
+                self.plane,
+            ),
+        )
+    }
+
+    /// Box around every place the shape passes through. Boxes at evenly spaced times are
+    /// padded by the furthest any point can travel before the next one.
+    fn sweep(&self) -> Aabb {
+        let inner = self.shape.bounding_box();
+        let corners = (0..8).map(|i| {
+            Vec3::new(
+                if i & 1 == 0 { inner.min.x } else { inner.max.x },
+                if i & 2 == 0 { inner.min.y } else { inner.max.y },
+                if i & 4 == 0 { inner.min.z } else { inner.max.z },
+            )
+        });
+        let reach = corners
+            .clone()
+            .map(|corner| (corner - self.pivot).mag())
+            .fold(0.0, f32::max);
+        let angle = (self.end.1 - self.start.1).abs();
+        let travel = (self.end.0 - self.start.0).mag() + reach * angle;
+        let pad = Vec3::one() * (travel / (2 * BOUND_STEPS) as f32);
+        (0..=BOUND_STEPS)
+            .map(|step| {
+                let (translation, rotation) = self.at(step as f32 / BOUND_STEPS as f32);
+                let (min, max) = corners.clone().fold(
+                    (Vec3::one() * f32::MAX, Vec3::one() * f32::MIN),
+                    |(min, max), corner| {
+                        let moved = self.place(corner, translation, rotation);
+                        (min.min_by_component(moved), max.max_by_component(moved))
+                    },
+                );
+                Aabb::new(min - pad, max + pad)
+            })
+            .reduce(Aabb::surrounding_box)
+            .unwrap()
+    }
+
+    #[inline]
+    fn place(&self, point: Vec3, translation: Vec3, rotation: Rotor3) -> Vec3 {
+        (point - self.pivot).rotated_by(rotation) + self.pivot + translation
+    }
+}
+
+impl Hittable for Moving {
+    fn hit(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>> {
+        let (translation, rotation) = self.at(ray.time);
+        let back = rotation.reversed();
+        let local = Ray {
+            pos: (ray.pos - self.pivot - translation).rotated_by(back) + self.pivot,
+            dir: ray.dir.rotated_by(back),
+            ..*ray
+        };
+        // The move is rigid, so distances along the ray stay the same
+        self.shape
+            .hit(&local, t_min, t_max, sampler)
+            .map(|hit| HitRecord {
+                point: ray.at(hit.t),
+                normal: hit.normal.rotated_by(rotation),
+                uv: hit.uv.rotated_by(rotation),
+                ..hit
+            })
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.bounds
+    }
+
+    fn area(&self) -> f32 {
+        self.shape.area()
+    }
+}
@@ -1,13 +1,19 @@
-use crate::{material::Material, ray::Ray};
+use std::f32::consts::PI;
 
-use ultraviolet::Vec3;
+use crate::{
+    material::Material,
+    random::{quasirandom_in_cone, quasirandom_on_uniform_sphere, Sampler},
+    ray::{Onb, Ray},
+};
+
+use ultraviolet::{Vec2, Vec3};
 
 use super::{
     cube::Aabb,
-    hittable::{HitRecord, Hittable},
+    hittable::{HitRecord, Hittable, Uv},
 };
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct Sphere {
     pub center: Vec3,
     pub radius: f32,
@@ -22,10 +28,36 @@ impl Sphere {
             material,
         }
     }
+
+    /// Longitude around the y axis and latitude down from the top pole, both zero to one.
+    #[inline]
+    fn uv(&self, point: Vec3) -> Uv {
+        let local = point - self.center;
+        let radius = self.radius.abs();
+        let ring = local.x.hypot(local.z).max(1e-6);
+        let phi = local.z.atan2(local.x);
+        let phi = if phi < 0.0 { phi + 2.0 * PI } else { phi };
+        let theta = (local.y / radius).clamp(-1.0, 1.0).acos();
+        let dpdu = Vec3::new(-local.z, 0.0, local.x) * (2.0 * PI);
+        let dpdv = Vec3::new(local.y * local.x / ring, -ring, local.y * local.z / ring) * PI;
+        Uv {
+            point: Vec2::new(phi / (2.0 * PI), theta / PI),
+            dpdu,
+            dpdv,
+            dndu: dpdu / radius,
+            dndv: dpdv / radius,
+        }
+    }
 }
 impl Hittable for Sphere {
     #[inline]
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        _sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>> {
         let oc = ray.pos - self.center;
         let half_b = oc.dot(ray.dir);
         let disc = half_b.powi(2) - (oc.mag_sq() - (self.radius.powi(2)));
@@ -35,23 +67,29 @@ impl Hittable for Sphere {
             let mut temp = -half_b - h;
             if temp < t_max && temp > t_min {
                 let hit_point = ray.at(temp);
-                return Some(HitRecord::new(
-                    temp,
-                    hit_point,
-                    (hit_point - self.center).normalized(),
-                    &self.material,
-                ));
+                return Some(
+                    HitRecord::new(
+                        temp,
+                        hit_point,
+                        (hit_point - self.center).normalized(),
+                        &self.material,
+                    )
+                    .with_uv(self.uv(hit_point)),
+                );
             }
 
             temp = -half_b + h;
             if temp < t_max && temp > t_min {
                 let hit_point = ray.at(temp);
-                return Some(HitRecord::new(
-                    temp,
-                    hit_point,
-                    (hit_point - self.center).normalized(),
-                    &self.material,
-                ));
+                return Some(
+                    HitRecord::new(
+                        temp,
+                        hit_point,
+                        (hit_point - self.center).normalized(),
+                        &self.material,
+                    )
+                    .with_uv(self.uv(hit_point)),
+                );
             }
         }
         None
@@ -63,4 +101,50 @@ impl Hittable for Sphere {
             max: self.center + Vec3::one() * self.radius.abs(),
         }
     }
+
+    fn pdf_value(&self, origin: Vec3, dir: Vec3) -> f32 {
+        let ray = Ray::new(origin, dir);
+        // A sphere never draws from the sampler
+        if let Some(hit) = self.hit(&ray, 0.0001, f32::MAX, &mut Sampler::from_seed(0)) {
+            let dist_sq = (self.center - origin).mag_sq();
+            let radius_sq = self.radius.powi(2);
+            if dist_sq > radius_sq {
+                // Uniform over the cone of directions subtended by the sphere
+                let cos_theta_max = (1.0 - radius_sq / dist_sq).sqrt();
+                (2.0 * PI * (1.0 - cos_theta_max)).recip()
+            } else {
+                // Inside the sphere, points are picked uniformly by area
+                let cosine = hit.normal.dot(ray.dir).abs();
+                hit.t.powi(2) / (cosine * 4.0 * PI * radius_sq)
+            }
+        } else {
+            0.0
+        }
+    }
+
+    fn random(&self, origin: Vec3, r1: f32, r2: f32) -> Vec3 {
+        let direction = self.center - origin;
+        let dist_sq = direction.mag_sq();
+        let radius_sq = self.radius.powi(2);
+        if dist_sq > radius_sq {
+            let cos_theta_max = (1.0 - radius_sq / dist_sq).sqrt();
+            Onb::from_w(&direction).local(quasirandom_in_cone(cos_theta_max, r1, r2))
+        } else {
+            self.center + self.radius.abs() * quasirandom_on_uniform_sphere(r1, r2) - origin
+        }
+    }
+
+    fn area(&self) -> f32 {
+        4.0 * PI * self.radius.powi(2)
+    }
+
+    fn sample_surface(&self, r1: f32, r2: f32) -> Option<HitRecord<'_>> {
+        let normal = quasirandom_on_uniform_sphere(r1, r2);
+        Some(HitRecord::new(
+            0.0,
+            self.center + self.radius.abs() * normal,
+            normal,
+            &self.material,
+        ))
+    }
 }
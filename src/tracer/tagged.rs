@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use ultraviolet::Vec3;
+
+use crate::{random::Sampler, ray::Ray};
+
+use super::{
+    cube::Aabb,
+    hittable::{HitRecord, Hittable},
+};
+
+/// Stamps the scene's object and material index onto every hit of the wrapped shape.
+pub struct Tagged {
+    object_index: u32,
+    material_index: u32,
+    shape: Arc<dyn Hittable + Send + Sync>,
+}
+
+impl Tagged {
+    pub fn new(
+        object_index: u32,
+        material_index: u32,
+        shape: Arc<dyn Hittable + Send + Sync>,
+    ) -> Tagged {
+        Tagged {
+            object_index,
+            material_index,
+            shape,
+        }
+    }
+}
+
+impl Hittable for Tagged {
+    fn hit(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>> {
+        self.shape
+            .hit(ray, t_min, t_max, sampler)
+            .map(|hit| HitRecord {
+                object_index: self.object_index,
+                material_index: self.material_index,
+                ..hit
+            })
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.shape.bounding_box()
+    }
+
+    fn pdf_value(&self, origin: Vec3, dir: Vec3) -> f32 {
+        self.shape.pdf_value(origin, dir)
+    }
+
+    fn random(&self, origin: Vec3, r1: f32, r2: f32) -> Vec3 {
+        self.shape.random(origin, r1, r2)
+    }
+
+    fn area(&self) -> f32 {
+        self.shape.area()
+    }
+
+    fn sample_surface(&self, r1: f32, r2: f32) -> Option<HitRecord<'_>> {
+        self.shape.sample_surface(r1, r2).map(|hit| HitRecord {
+            object_index: self.object_index,
+            material_index: self.material_index,
+            ..hit
+        })
+    }
+}
@@ -1,18 +1,23 @@
 use std::sync::Arc;
 
-use crate::{material::Material, ray::Ray};
+use crate::{
+    material::Material,
+    random::Sampler,
+    ray::{Onb, Ray},
+};
 
-use ultraviolet::Vec3;
+use ultraviolet::{Vec2, Vec3};
 
 use super::{
     cube::Aabb,
-    hittable::{HitRecord, Hittable},
+    hittable::{HitRecord, Hittable, Uv},
 };
 
 #[derive(Debug, Clone)]
 pub struct Triangle {
     pub vertices: [Vec3; 3],
     pub normals: [Vec3; 3],
+    pub uvs: [Vec2; 3],
     pub material: Arc<Material>,
     two_sided: bool,
 }
@@ -27,14 +32,67 @@ impl Triangle {
         Triangle {
             vertices,
             normals,
+            uvs: [Vec2::zero(), Vec2::unit_x(), Vec2::one()],
             material: Arc::new(material),
             two_sided,
         }
     }
+
+    pub fn with_uvs(self, uvs: [Vec2; 3]) -> Triangle {
+        Triangle { uvs, ..self }
+    }
+
+    /// Texture coordinates at barycentric `u` and `v`, with the point and normal changing
+    /// across the face as the vertex uvs say.
+    #[inline]
+    fn uv(&self, u: f32, v: f32) -> Uv {
+        let point = (1.0 - (u + v)) * self.uvs[0] + u * self.uvs[1] + v * self.uvs[2];
+        let (duv02, duv12) = (self.uvs[0] - self.uvs[2], self.uvs[1] - self.uvs[2]);
+        let det = duv02.x * duv12.y - duv02.y * duv12.x;
+        if det.abs() < 1e-12 {
+            // Degenerate uvs, so any frame in the plane of the face will do
+            let frame = Onb::from_w(
+                &(self.vertices[1] - self.vertices[0]).cross(self.vertices[2] - self.vertices[0]),
+            );
+            return Uv {
+                point,
+                dpdu: frame.u,
+                dpdv: frame.v,
+                ..Uv::default()
+            };
+        }
+        let along = |d02: Vec3, d12: Vec3| {
+            (
+                (duv12.y * d02 - duv02.y * d12) / det,
+                (duv02.x * d12 - duv12.x * d02) / det,
+            )
+        };
+        let (dpdu, dpdv) = along(
+            self.vertices[0] - self.vertices[2],
+            self.vertices[1] - self.vertices[2],
+        );
+        let (dndu, dndv) = along(
+            self.normals[0] - self.normals[2],
+            self.normals[1] - self.normals[2],
+        );
+        Uv {
+            point,
+            dpdu,
+            dpdv,
+            dndu,
+            dndv,
+        }
+    }
 }
 
 impl Hittable for Triangle {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        _sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>> {
         let edge1 = self.vertices[1] - self.vertices[0];
         let edge2 = self.vertices[2] - self.vertices[0];
         let h = ray.dir.cross(edge2);
@@ -69,12 +127,7 @@ impl Hittable for Triangle {
             ((1.0 - (u + v)) * self.normals[0] + u * self.normals[1] + v * self.normals[2])
                 .normalized();
 
-        Some(HitRecord {
-            t,
-            point: ray.at(t),
-            normal,
-            material: &self.material,
-        })
+        Some(HitRecord::new(t, ray.at(t), normal, &self.material).with_uv(self.uv(u, v)))
     }
 
     fn bounding_box(&self) -> Aabb {
@@ -103,4 +156,44 @@ impl Hittable for Triangle {
             ),
         }
     }
+
+    fn pdf_value(&self, origin: Vec3, dir: Vec3) -> f32 {
+        let ray = Ray::new(origin, dir);
+        // A triangle never draws from the sampler
+        if let Some(hit) = self.hit(&ray, 0.0001, f32::MAX, &mut Sampler::from_seed(0)) {
+            let cross =
+                (self.vertices[1] - self.vertices[0]).cross(self.vertices[2] - self.vertices[0]);
+            let area = 0.5 * cross.mag();
+            let cosine = cross.normalized().dot(ray.dir).abs();
+            hit.t.powi(2) / (cosine * area)
+        } else {
+            0.0
+        }
+    }
+
+    fn random(&self, origin: Vec3, r1: f32, r2: f32) -> Vec3 {
+        // Uniform barycentric coordinates
+        let su = r1.sqrt();
+        let (b0, b1) = (1.0 - su, r2 * su);
+        let point =
+            b0 * self.vertices[0] + b1 * self.vertices[1] + (1.0 - b0 - b1) * self.vertices[2];
+        point - origin
+    }
+
+    fn area(&self) -> f32 {
+        0.5 * (self.vertices[1] - self.vertices[0])
+            .cross(self.vertices[2] - self.vertices[0])
+            .mag()
+    }
+
+    fn sample_surface(&self, r1: f32, r2: f32) -> Option<HitRecord<'_>> {
+        let su = r1.sqrt();
+        let weights = [1.0 - su, r2 * su, su - r2 * su];
+        let point = (0..3).map(|i| weights[i] * self.vertices[i]).sum();
+        let normal = (0..3)
+            .map(|i| weights[i] * self.normals[i])
+            .sum::<Vec3>()
+            .normalized();
+        Some(HitRecord::new(0.0, point, normal, &self.material))
+    }
 }